@@ -0,0 +1,1540 @@
+//! Command-line interface: argument parsing, logging and the end-of-run report
+
+mod config;
+
+use chrono::Local;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use clap_complete::{Generator, Shell};
+use console::Term;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{Level, LevelFilter, Log, Record, set_max_level, warn};
+use num_format::{Locale, ToFormattedString};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    Codec, Delimiter, Field, GatherOptions, LineCallback, MIN_BUFFER_SIZE, MatchKind, Mode,
+    ProgressSink, RunOptions, RunSummary, SieveError, SieveOptions, build_glob_set, gather_files,
+    is_url, limit_files, lock_run, process_files, read_checkpoint, read_file_list,
+    skip_checkpointed,
+};
+use config::Config;
+
+#[derive(Parser, Debug)]
+pub(crate) struct Args {
+    /// Root directory, or a single file to process
+    #[arg(required_unless_present = "generate_completions")]
+    pub(crate) root_dir: Option<String>,
+
+    /// Patterns
+    pub(crate) patterns: Vec<String>,
+
+    /// Mode: remove matching lines or keep only matching lines
+    #[arg(long, value_enum, default_value = "remove")]
+    pub(crate) mode: Mode,
+
+    /// Keep matching lines but replace each match within them with REPLACEMENT (e.g. ***); with --regex, $1 or ${name} insert capture groups
+    #[arg(long, value_name = "REPLACEMENT", conflicts_with = "mode")]
+    pub(crate) redact: Option<String>,
+
+    /// Treat patterns as regular expressions instead of plain substrings
+    #[arg(long)]
+    pub(crate) regex: bool,
+
+    /// Only match patterns at the start of a line
+    #[arg(long, conflicts_with_all = ["regex", "ends_with", "exact"])]
+    pub(crate) starts_with: bool,
+
+    /// Only match patterns at the end of a line
+    #[arg(long, conflicts_with_all = ["regex", "exact"])]
+    pub(crate) ends_with: bool,
+
+    /// Only match lines that match every pattern, instead of any one of them
+    #[arg(long)]
+    pub(crate) match_all: bool,
+
+    /// Match patterns (and --keep patterns) only against the Nth field of each line (counting from 1)
+    #[arg(long, value_name = "N")]
+    pub(crate) field: Option<usize>,
+
+    /// Separator between the fields selected by --field
+    #[arg(long, value_name = "BYTE", default_value = "\\t", value_parser = parse_delimiter)]
+    pub(crate) field_delimiter: Delimiter,
+
+    /// Drop lines with fewer fields than --field instead of keeping them
+    #[arg(long, requires = "field", conflicts_with = "redact")]
+    pub(crate) drop_missing_field: bool,
+
+    /// Filter the text files inside .tar.gz (and other compressed .tar) archives, keeping the rest of each archive as is
+    #[arg(long)]
+    pub(crate) tar: bool,
+
+    /// Pass the first N lines of each file through unchanged and leave them out of the counts
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) skip_lines: u64,
+
+    /// Always keep the first N lines of each file (after any --skip-lines), whatever they contain
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) keep_header: u64,
+
+    /// Collapse runs of identical kept lines into one, like uniq (removed lines don't break a run)
+    #[arg(long)]
+    pub(crate) dedup_consecutive: bool,
+
+    /// Never drop lines matching this pattern, even if they match the others (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub(crate) keep: Vec<String>,
+
+    /// Only match lines that equal a pattern exactly
+    #[arg(long, conflicts_with = "regex")]
+    pub(crate) exact: bool,
+
+    /// Ignore whitespace at either end of each line (or --field) when matching; kept lines are written unchanged
+    #[arg(long)]
+    pub(crate) trim: bool,
+
+    /// Decode \t, \n, \r, \0, \\ and \xNN in patterns (and --keep patterns) before matching
+    #[arg(long, conflicts_with = "regex")]
+    pub(crate) decode_escapes: bool,
+
+    /// Report what would be filtered without modifying any files
+    #[arg(long)]
+    pub(crate) dry_run: bool,
+
+    /// With --dry-run, print the lines that would go as a unified diff of each file
+    #[arg(long, requires = "dry_run", conflicts_with = "format")]
+    pub(crate) diff: bool,
+
+    /// Only count matching lines, skipping all writing and recompression
+    #[arg(long, conflicts_with_all = ["backup", "output_dir", "removed_output"])]
+    pub(crate) count_only: bool,
+
+    /// Like grep -c: print each file's number of matching lines as PATH:COUNT instead of the summary, changing nothing
+    #[arg(short = 'c', long, conflicts_with_all = ["backup", "output_dir", "removed_output", "format", "diff"])]
+    pub(crate) count: bool,
+
+    /// Filter and recompress every file without keeping the output, then report throughput and per-file latency
+    #[arg(long, conflicts_with_all = [
+        "dry_run", "count_only", "count", "backup", "output_dir", "removed_output", "format",
+        "verify", "checkpoint", "skip_processed", "interactive",
+    ])]
+    pub(crate) benchmark: bool,
+
+    /// Back up each original file before overwriting it, appending SUFFIX to its name
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = ".bak")]
+    pub(crate) backup: Option<String>,
+
+    /// Leave an existing backup in place instead of failing the file
+    #[arg(long, requires = "backup")]
+    pub(crate) keep_existing_backup: bool,
+
+    /// Write filtered files under this directory, mirroring the source tree, instead of in place
+    #[arg(long, conflicts_with = "backup")]
+    pub(crate) output_dir: Option<PathBuf>,
+
+    /// Append every removed line, prefixed with its source file and a tab, to this file
+    #[arg(long)]
+    pub(crate) removed_output: Option<PathBuf>,
+
+    /// Extensions of uncompressed text files to process as well (e.g. txt,log)
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) extensions: Vec<String>,
+
+    /// Also treat files starting with the gzip magic bytes as gzip, whatever their extension
+    #[arg(long)]
+    pub(crate) detect_magic: bool,
+
+    /// Byte that separates records: a single character or one of \0, \t, \n, \r
+    #[arg(long, value_name = "BYTE", default_value = "\\n", value_parser = parse_delimiter)]
+    pub(crate) delimiter: Delimiter,
+
+    /// Delete files that would be left with no lines instead of rewriting them empty
+    #[arg(long)]
+    pub(crate) delete_emptied: bool,
+
+    /// Leave files that would be left with no lines untouched instead of rewriting them empty
+    #[arg(long, conflicts_with = "delete_emptied")]
+    pub(crate) no_empty: bool,
+
+    /// Leave a file untouched and report an error if more than this fraction of its lines would be dropped (0.0-1.0)
+    #[arg(long, value_name = "RATIO", value_parser = parse_ratio)]
+    pub(crate) max_removal_ratio: Option<f64>,
+
+    /// Keep lines that aren't valid UTF-8 (matching them lossily) instead of failing the file
+    #[arg(long)]
+    pub(crate) binary_safe: bool,
+
+    /// Compression level for rewritten files (0-9, defaults to each format's default)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub(crate) compression_level: Option<u32>,
+
+    /// Write rewritten files in this format, renaming them to match (e.g. app.log.gz to app.log.zst)
+    #[arg(long, value_enum, value_name = "CODEC")]
+    pub(crate) output_codec: Option<Codec>,
+
+    /// Size of the read and write buffers around each file (e.g. 64K, 1M) [default: 8K]
+    #[arg(long, value_name = "BYTES", value_parser = parse_buffer_size)]
+    pub(crate) buffer_size: Option<usize>,
+
+    /// Experimental: filter files of at least SIZE on several threads at once (e.g. 1G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub(crate) parallel_above: Option<u64>,
+
+    /// Compress gzip output in blocks on several threads at once, like pigz
+    #[arg(long)]
+    pub(crate) parallel_gzip: bool,
+
+    /// Limit how fast lines are read, in bytes of uncompressed data per second across all threads (e.g. 50M)
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    pub(crate) max_throughput: Option<u64>,
+
+    /// Memory-map large files instead of reading them; files must not be modified by anything else during the run
+    #[arg(long)]
+    pub(crate) mmap: bool,
+
+    /// Decompress each rewritten file before it replaces the original (reads every output twice)
+    #[arg(long, conflicts_with_all = ["dry_run", "count_only"])]
+    pub(crate) verify: bool,
+
+    /// Flush each rewritten file and its directory to disk before counting it as done
+    #[arg(long)]
+    pub(crate) fsync: bool,
+
+    /// When a file fails part way through, keep its partial output as <NAME>.sieve-partial for debugging
+    #[arg(long)]
+    pub(crate) keep_temp_on_error: bool,
+
+    /// Rewrite read-only files too, temporarily making them writable if needed
+    #[arg(long)]
+    pub(crate) force: bool,
+
+    /// Don't restore the original modification time on rewritten files
+    #[arg(long = "no-preserve-metadata", action = ArgAction::SetFalse)]
+    pub(crate) preserve_metadata: bool,
+
+    /// Process the files listed in this file, one per line ("-" for stdin), instead of searching the roots
+    #[arg(long, value_name = "PATH")]
+    pub(crate) files_from: Option<PathBuf>,
+
+    /// Another root directory to search as well (repeatable)
+    #[arg(long = "root", value_name = "DIR", conflicts_with = "output_dir")]
+    pub(crate) extra_roots: Vec<String>,
+
+    /// Maximum directory depth to descend below the root (0 = only files directly in the root)
+    #[arg(long)]
+    pub(crate) max_depth: Option<usize>,
+
+    /// Only process files whose name matches this glob (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub(crate) include: Vec<String>,
+
+    /// Skip files whose name matches this glob (repeatable, takes precedence over --include)
+    #[arg(long, value_name = "GLOB")]
+    pub(crate) exclude: Vec<String>,
+
+    /// Skip files smaller than this size (e.g. 512K, 10M, 2G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub(crate) min_size: Option<u64>,
+
+    /// Skip files larger than this size (e.g. 512K, 10M, 2G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub(crate) max_size: Option<u64>,
+
+    /// Only process files modified within this long ago (e.g. 30m, 24h, 7d)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub(crate) modified_since: Option<Duration>,
+
+    /// Only process files last modified more than this long ago (e.g. 30m, 24h, 7d)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub(crate) modified_before: Option<Duration>,
+
+    /// Follow symbolic links to directories while searching for files
+    #[arg(long)]
+    pub(crate) follow_symlinks: bool,
+
+    /// Skip files marked as processed by an earlier run, and mark the ones processed now
+    #[arg(long)]
+    pub(crate) skip_processed: bool,
+
+    /// Process only the first N files found, in path order (e.g. to try out patterns on a large tree)
+    #[arg(long, value_name = "N")]
+    pub(crate) limit: Option<usize>,
+
+    /// Number of threads (defaults to number of logical CPUs)
+    #[arg(long, value_parser = parse_threads)]
+    pub(crate) threads: Option<usize>,
+
+    /// Process one file at a time in path order, so the log comes out the same on every run
+    #[arg(long, conflicts_with = "threads")]
+    pub(crate) sequential: bool,
+
+    /// Log output destination
+    #[arg(long, value_enum, default_value = "file")]
+    pub(crate) log_output: LogOutput,
+
+    /// Log record format: plain text, or one JSON object per line
+    #[arg(long, value_enum, default_value = "text")]
+    pub(crate) log_format: LogFormat,
+
+    /// Locale for number formatting [default: from LC_ALL, LC_NUMERIC or LANG, else en]
+    #[arg(long)]
+    pub(crate) locale: Option<String>,
+
+    /// Summary output format
+    #[arg(long, value_enum, default_value = "text")]
+    pub(crate) format: OutputFormat,
+
+    /// Count the lines each pattern matched and list them after the summary
+    #[arg(long)]
+    pub(crate) stats: bool,
+
+    /// List how many files lost 0, 1-10, 11-100, ... lines after the summary
+    #[arg(long)]
+    pub(crate) histogram: bool,
+
+    /// Log a warning naming every pattern that matched nothing, as these are often typos
+    #[arg(long)]
+    pub(crate) warn_unused_patterns: bool,
+
+    /// Show how many files were found and ask for confirmation before changing any
+    #[arg(long)]
+    pub(crate) interactive: bool,
+
+    /// Ask before processing files larger than SIZE (e.g. 1G), each or all at once
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub(crate) prompt_above: Option<u64>,
+
+    /// With --interactive or --prompt-above, go ahead without asking when stdin isn't a terminal
+    /// (otherwise abort, or skip the large files)
+    #[arg(long)]
+    pub(crate) yes: bool,
+
+    /// Show the progress bar even when stderr isn't a terminal
+    #[arg(long, conflicts_with = "quiet")]
+    pub(crate) progress: bool,
+
+    /// List up to N removed lines after the summary
+    #[arg(long, value_name = "N")]
+    pub(crate) show_samples: Option<usize>,
+
+    /// Hide the progress bar and summary (logging is unaffected)
+    #[arg(long, short)]
+    pub(crate) quiet: bool,
+
+    /// Write the run's counts to PATH in Prometheus text format, for node_exporter's textfile collector
+    #[arg(long, value_name = "PATH")]
+    pub(crate) metrics_file: Option<PathBuf>,
+
+    /// Record each processed file in PATH and skip files already recorded there, to resume an interrupted run
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["dry_run", "count_only"])]
+    pub(crate) checkpoint: Option<PathBuf>,
+
+    /// Hold an exclusive lock on PATH while running, and refuse to start if another run holds it
+    #[arg(long, value_name = "PATH")]
+    pub(crate) lock_file: Option<PathBuf>,
+
+    /// With --lock-file, wait for another run to release the lock instead of refusing to start
+    #[arg(long)]
+    pub(crate) wait_for_lock: bool,
+
+    /// Abort the run on the first file that fails instead of continuing with the rest
+    #[arg(long)]
+    pub(crate) fail_fast: bool,
+
+    /// Fail instead of warning: on an unknown locale, a pattern that never matched, an unreadable path or a failing file
+    #[arg(long)]
+    pub(crate) strict: bool,
+
+    /// Exit with 0 on success even when lines were removed
+    #[arg(long)]
+    pub(crate) no_exit_status: bool,
+
+    /// Read default settings from this TOML file [default: sieve.toml, if present]
+    #[arg(long, value_name = "PATH")]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Print a completion script for SHELL and exit
+    #[arg(long, value_name = "SHELL", hide = true)]
+    pub(crate) generate_completions: Option<Shell>,
+}
+
+impl Args {
+    /// Every root directory to search, the positional one first
+    pub(crate) fn roots(&self) -> Vec<PathBuf> {
+        self.root_dir
+            .iter()
+            .chain(&self.extra_roots)
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    /// File selection settings derived from the arguments
+    pub(crate) fn gather_options(&self) -> Result<GatherOptions, SieveError> {
+        Ok(GatherOptions {
+            plain_extensions: self.extensions.clone(),
+            detect_magic: self.detect_magic,
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+            include: build_glob_set(&self.include)?,
+            exclude: build_glob_set(&self.exclude)?,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            modified_after: self.modified_since.map(|age| SystemTime::now() - age),
+            modified_before: self.modified_before.map(|age| SystemTime::now() - age),
+            skip_processed: self.skip_processed,
+            strict: self.strict,
+        })
+    }
+
+    /// Run-wide settings derived from the arguments
+    pub(crate) fn run_options(&self) -> Result<RunOptions, SieveError> {
+        let checkpoint = match &self.checkpoint {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| SieveError::FileOpen {
+                        path: path.display().to_string(),
+                        source: e,
+                    })?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+
+        Ok(RunOptions {
+            threads: self.threads,
+            progress: None,
+            fail_fast: self.fail_fast,
+            sequential: self.sequential,
+            cancelled: Arc::default(),
+            checkpoint,
+            file_results: self.count,
+            histogram: self.histogram,
+            mark_processed: self.skip_processed,
+            time_files: self.benchmark,
+        })
+    }
+
+    /// Per-file filtering settings derived from the arguments
+    pub(crate) fn sieve_options(&self, root: &Path) -> Result<SieveOptions, SieveError> {
+        let decode = |patterns: &[String]| -> Result<Vec<String>, SieveError> {
+            if !self.decode_escapes {
+                return Ok(patterns.to_vec());
+            }
+            patterns
+                .iter()
+                .map(|pattern| decode_escapes(pattern))
+                .collect::<Result<_, _>>()
+                .map_err(|e| SieveError::InvalidOptions(format!("--decode-escapes: {e}")))
+        };
+        let mut builder = SieveOptions::builder(decode(&self.patterns)?)
+            .match_kind(self.match_kind())
+            .mode(self.mode())
+            .match_all(self.match_all)
+            .trim(self.trim)
+            .keep_patterns(decode(&self.keep)?)
+            .tar(self.tar)
+            .skip_lines(self.skip_lines)
+            .keep_header(self.keep_header)
+            .dedup_consecutive(self.dedup_consecutive)
+            .field(self.field.map(|number| Field {
+                number,
+                separator: self.field_delimiter,
+            }))
+            .drop_missing_field(self.drop_missing_field)
+            .replacement(self.redact.clone().unwrap_or_default())
+            .dry_run(self.dry_run)
+            .count_only(self.count_only || self.count)
+            .plain_extensions(self.extensions.clone())
+            .delimiter(self.delimiter)
+            .delete_emptied(self.delete_emptied)
+            .no_empty(self.no_empty)
+            .max_removal_ratio(self.max_removal_ratio)
+            .binary_safe(self.binary_safe)
+            .compression_level(self.compression_level)
+            .output_codec(self.output_codec)
+            .buffer_size(self.buffer_size)
+            .pattern_hits(self.stats)
+            .matched_patterns(self.warn_unused_patterns || self.strict)
+            .mmap(self.mmap)
+            .parallel_above(self.parallel_above)
+            .parallel_gzip(self.parallel_gzip)
+            .benchmark(self.benchmark)
+            .strict(self.strict)
+            .max_throughput(self.max_throughput)
+            .verify(self.verify)
+            .fsync(self.fsync)
+            .keep_temp_on_error(self.keep_temp_on_error)
+            .preserve_metadata(self.preserve_metadata)
+            .force(self.force)
+            .backup_suffix(self.backup.clone())
+            .keep_existing_backup(self.keep_existing_backup);
+        if let Some(dir) = &self.output_dir {
+            builder = builder.output_dir(root, dir);
+        }
+        if self.diff {
+            builder = builder.diff_output(std::io::stdout());
+        }
+        if let Some(path) = &self.removed_output {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| SieveError::FileOpen {
+                    path: path.display().to_string(),
+                    source: e,
+                })?;
+            builder = builder.removed_output(file);
+        }
+        builder.build()
+    }
+
+    /// What happens to matching lines; `--redact` implies redact mode
+    pub(crate) fn mode(&self) -> Mode {
+        if self.redact.is_some() {
+            Mode::Redact
+        } else {
+            self.mode.clone()
+        }
+    }
+
+    /// The locale for numbers: `--locale` if given, else the environment's, else `en`
+    pub(crate) fn locale(&self) -> String {
+        self.locale
+            .clone()
+            .or_else(|| locale_from_env(|name| std::env::var(name).ok()))
+            .unwrap_or_else(|| "en".to_string())
+    }
+
+    /// How patterns should be compared against each line
+    pub(crate) fn match_kind(&self) -> MatchKind {
+        if self.regex {
+            MatchKind::Regex
+        } else if self.starts_with {
+            MatchKind::StartsWith
+        } else if self.ends_with {
+            MatchKind::EndsWith
+        } else if self.exact {
+            MatchKind::Exact
+        } else {
+            MatchKind::Contains
+        }
+    }
+}
+
+/// Parse a `--delimiter` value: one ASCII character or a backslash escape
+pub(crate) fn parse_delimiter(value: &str) -> Result<Delimiter, String> {
+    let byte = match value {
+        "\\0" => 0,
+        "\\t" => b'\t',
+        "\\n" => b'\n',
+        "\\r" => b'\r',
+        "\\\\" => b'\\',
+        _ if value.len() == 1 && value.is_ascii() => value.as_bytes()[0],
+        _ => {
+            return Err(format!(
+                "expected a single ASCII character or one of \\0, \\t, \\n, \\r, got '{value}'"
+            ));
+        }
+    };
+    Ok(Delimiter(byte))
+}
+
+/// Decode the backslash escapes in a pattern given with `--decode-escapes`: `\t`, `\n`,
+/// `\r`, `\0`, `\\` and `\xNN` for an ASCII byte
+pub(crate) fn decode_escapes(pattern: &str) -> Result<String, String> {
+    let mut decoded = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let digits = hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit());
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if digits && byte.is_ascii() => char::from(byte),
+                    _ => {
+                        return Err(format!(
+                            "\\x{hex} in '{pattern}' isn't an ASCII byte from \\x00 to \\x7f"
+                        ));
+                    }
+                }
+            }
+            Some(other) => return Err(format!("unknown escape \\{other} in '{pattern}'")),
+            None => return Err(format!("'{pattern}' ends in a lone backslash")),
+        };
+        decoded.push(escaped);
+    }
+    Ok(decoded)
+}
+
+/// Parse a `--threads` value, which must be at least 1
+pub(crate) fn parse_threads(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1; leave --threads out to use every CPU".to_string()),
+        Ok(threads) => Ok(threads),
+        Err(_) => Err(format!("'{value}' is not a whole number")),
+    }
+}
+
+/// Parse a `--max-removal-ratio` value, which must lie between 0 and 1
+pub(crate) fn parse_ratio(value: &str) -> Result<f64, String> {
+    let ratio: f64 = value
+        .parse()
+        .map_err(|_| format!("'{value}' is not a number"))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("{ratio} is not between 0.0 and 1.0"));
+    }
+    Ok(ratio)
+}
+
+/// Parse a size such as `4096`, `512K`, `10M` or `2G` (binary multiples, case-insensitive)
+pub(crate) fn parse_size(value: &str) -> Result<u64, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &upper[digits.len()..] {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        suffix => return Err(format!("unknown size suffix '{suffix}' in '{value}'")),
+    };
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{value}' is not a size"))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("'{value}' is too large"))
+}
+
+/// Parse a buffer size, which uses the same suffixes as file sizes but has a floor
+pub(crate) fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    let size = parse_size(value)?;
+    let size = usize::try_from(size).map_err(|_| format!("'{value}' is too large"))?;
+    if size < MIN_BUFFER_SIZE {
+        return Err(format!(
+            "'{value}' is below the minimum buffer size of {MIN_BUFFER_SIZE} bytes"
+        ));
+    }
+    Ok(size)
+}
+
+/// Parse a duration such as `90s`, `30m`, `24h`, `7d` or `2w`
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit_seconds: u64 = match &value[digits.len()..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "" => return Err(format!("'{value}' needs a unit: s, m, h, d or w")),
+        unit => return Err(format!("unknown duration unit '{unit}' in '{value}'")),
+    };
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{value}' is not a duration"))?;
+    number
+        .checked_mul(unit_seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("'{value}' is too long"))
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub(crate) enum LogOutput {
+    File,
+    Stdout,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub(crate) enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Config file picked up from the working directory when `--config` isn't given
+pub(crate) const CONFIG_FILE_NAME: &str = "sieve.toml";
+
+/// Exit status for a successful run that removed at least one line
+pub(crate) const EXIT_LINES_REMOVED: u8 = 10;
+
+/// Run sieve with the process's command-line arguments
+pub fn run() -> Result<ExitCode, SieveError> {
+    let args = parse_args()?;
+    if let Some(shell) = args.generate_completions {
+        write_completions(shell, &mut std::io::stdout())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let log_file_name = setup_logging(&args.log_output, &args.log_format)?;
+    check_locale(&args)?;
+    check_patterns(&args)?;
+    // Released when the file is closed on return, however the run ends
+    let _lock = match &args.lock_file {
+        Some(path) => Some(lock_run(path, args.wait_for_lock)?),
+        None => None,
+    };
+
+    let roots = args
+        .roots()
+        .iter()
+        .map(|root| {
+            if is_url(root) {
+                Ok(root.clone())
+            } else {
+                root.canonicalize()
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Gather compressed files with sizes
+    let mut skipped = 0;
+    let (gz_files, total_size) = match &args.files_from {
+        Some(path) if path.as_os_str() == "-" => {
+            read_file_list(std::io::stdin().lock(), args.strict)?
+        }
+        Some(path) => {
+            let file = File::open(path).map_err(|e| SieveError::FileOpen {
+                path: path.display().to_string(),
+                source: e,
+            })?;
+            read_file_list(BufReader::new(file), args.strict)?
+        }
+        None => {
+            let gathered = gather_files(&roots, &args.gather_options()?)?;
+            skipped = gathered.skipped;
+            (gathered.files, gathered.total_size)
+        }
+    };
+    // Files dropped from here on are skipped too
+    let found = gz_files.len();
+    let (gz_files, total_size) = match &args.checkpoint {
+        Some(path) => skip_checkpointed(gz_files, &read_checkpoint(path)?),
+        None => (gz_files, total_size),
+    };
+    // After the checkpoint, so a resumed run takes the next files rather than none
+    let (gz_files, total_size) = match args.limit {
+        Some(limit) => limit_files(gz_files, limit),
+        None => (gz_files, total_size),
+    };
+    if args.output_dir.is_none()
+        && let Some((url, _)) = gz_files.iter().find(|(path, _)| is_url(path))
+    {
+        return Err(SieveError::InvalidOptions(format!(
+            "{} can't be filtered in place; use --output-dir",
+            url.display()
+        )));
+    }
+
+    let (gz_files, total_size) = match args.prompt_above {
+        Some(threshold) => {
+            let stdin = std::io::stdin();
+            confirm_large_files(
+                &mut stdin.lock(),
+                &mut std::io::stderr(),
+                gz_files,
+                threshold,
+                stdin.is_terminal(),
+                args.yes,
+            )?
+        }
+        None => (gz_files, total_size),
+    };
+
+    if args.interactive {
+        let stdin = std::io::stdin();
+        let proceed = confirm(
+            &mut stdin.lock(),
+            &mut std::io::stderr(),
+            gz_files.len(),
+            total_size,
+            stdin.is_terminal(),
+            args.yes,
+        )?;
+        if !proceed {
+            eprintln!("Aborted, no files were modified.");
+            if let Some(log_file) = log_file_name {
+                cleanup_empty_log_file(&log_file)?;
+            }
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+
+    // Let files in flight finish on Ctrl-C so no temp files or half-written output are left behind
+    let mut run_options = args.run_options()?;
+    run_options.progress = Some(Arc::new(progress_bar(
+        &args,
+        std::io::stderr().is_terminal(),
+    )));
+    let cancelled = Arc::clone(&run_options.cancelled);
+    if let Err(e) = ctrlc::set_handler(move || {
+        if cancelled.swap(true, Ordering::Relaxed) {
+            // A second Ctrl-C means the user doesn't want to wait
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted, finishing files in progress (Ctrl-C again to quit now)...");
+    }) {
+        warn!("Failed to install the Ctrl-C handler: {e}");
+    }
+
+    // Process files and display progress
+    // Only one root is allowed alongside --output-dir, so it's the one to mirror;
+    // a single file is mirrored relative to its directory
+    let mut options = args.sieve_options(mirror_root(&roots[0]))?;
+    let samples = Arc::new(Samples::new(args.show_samples.unwrap_or(0)));
+    if args.show_samples.is_some() {
+        let samples = Arc::clone(&samples);
+        options.on_removed = Some(LineCallback(Arc::new(move |line| samples.record(line))));
+    }
+    let mut summary = process_files(&gz_files, &options, total_size, &run_options)?;
+    summary.files_skipped = skipped + (found - gz_files.len()) as u64;
+
+    // Reported after the summary, so a strict run still shows what it did
+    let mut strict_failure = None;
+    if args.warn_unused_patterns || args.strict {
+        let unused = unused_patterns(&summary);
+        if !unused.is_empty() {
+            let message = format!("Patterns that never matched a line: {}", unused.join(", "));
+            if args.strict {
+                strict_failure = Some(SieveError::Strict(message));
+            } else {
+                warn!("{message}");
+            }
+        }
+        // Counted for the warning alone, not to be listed
+        if !args.stats {
+            summary.pattern_hits.clear();
+        }
+    }
+
+    if let Some(path) = &args.metrics_file {
+        write_metrics(path, &summary)?;
+    }
+
+    // Print summary report
+    report_summary(&mut std::io::stdout().lock(), &args, &summary)?;
+    if args.show_samples.is_some() && !args.quiet && args.format == OutputFormat::Text {
+        print_samples(&mut std::io::stdout().lock(), &samples.lines())?;
+    }
+    if let Some(e) = strict_failure {
+        return Err(e);
+    }
+
+    // Clean up empty log file if needed
+    if let Some(log_file) = log_file_name {
+        cleanup_empty_log_file(&log_file)?;
+    }
+
+    Ok(ExitCode::from(exit_status(&args, &summary)))
+}
+
+/// Ask whether to go ahead with `file_count` files totalling `total_size` bytes.
+/// Only a "y" or "yes" answer proceeds. Without a terminal to ask on, the answer is
+/// `assume_yes`.
+pub(crate) fn confirm(
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+    file_count: usize,
+    total_size: u64,
+    is_terminal: bool,
+    assume_yes: bool,
+) -> Result<bool, SieveError> {
+    if !is_terminal {
+        return Ok(assume_yes);
+    }
+    write!(
+        out,
+        "About to process {file_count} files ({}). Continue? [y/N] ",
+        HumanBytes(total_size)
+    )?;
+    out.flush()?;
+    Ok(matches!(read_answer(input)?.as_str(), "y" | "yes"))
+}
+
+/// Ask about the files larger than `threshold` before any are processed, returning the
+/// files to go ahead with and their total size. The large files can all be accepted or
+/// skipped at once, or asked about one by one. Without a terminal to ask on, they're
+/// kept if `assume_yes` and skipped with a warning otherwise.
+pub(crate) fn confirm_large_files(
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+    mut files: Vec<(PathBuf, u64)>,
+    threshold: u64,
+    is_terminal: bool,
+    assume_yes: bool,
+) -> Result<(Vec<(PathBuf, u64)>, u64), SieveError> {
+    let large: Vec<(PathBuf, u64)> = files
+        .iter()
+        .filter(|(_, size)| *size > threshold)
+        .cloned()
+        .collect();
+    let mut skipped = HashSet::new();
+    if !is_terminal {
+        if !assume_yes {
+            for (path, size) in large {
+                warn!(
+                    "Skipping {} ({}): larger than --prompt-above and there's no terminal to ask on",
+                    path.display(),
+                    HumanBytes(size)
+                );
+                skipped.insert(path);
+            }
+        }
+    } else if !large.is_empty() {
+        writeln!(
+            out,
+            "{} files are larger than {}:",
+            large.len(),
+            HumanBytes(threshold)
+        )?;
+        for (path, size) in &large {
+            writeln!(out, "  {} ({})", path.display(), HumanBytes(*size))?;
+        }
+        write!(out, "Process them? [y]es, [n]o, or [a]sk for each: ")?;
+        out.flush()?;
+        match read_answer(input)?.as_str() {
+            "y" | "yes" => {}
+            "a" | "ask" => {
+                for (path, size) in large {
+                    write!(
+                        out,
+                        "Process {} ({})? [y/N] ",
+                        path.display(),
+                        HumanBytes(size)
+                    )?;
+                    out.flush()?;
+                    if !matches!(read_answer(input)?.as_str(), "y" | "yes") {
+                        skipped.insert(path);
+                    }
+                }
+            }
+            _ => skipped.extend(large.into_iter().map(|(path, _)| path)),
+        }
+    }
+    files.retain(|(path, _)| !skipped.contains(path));
+    let total_size = files.iter().map(|(_, size)| size).sum();
+    Ok((files, total_size))
+}
+
+/// One line of input, trimmed and lowercased
+fn read_answer(input: &mut impl BufRead) -> std::io::Result<String> {
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim().to_ascii_lowercase())
+}
+
+/// Process exit status for a completed run: 1 if any file failed, otherwise
+/// `EXIT_LINES_REMOVED` when lines were removed (unless `--no-exit-status`), otherwise 0
+pub(crate) fn exit_status(args: &Args, summary: &RunSummary) -> u8 {
+    if summary.files_failed > 0 {
+        1
+    } else if summary.total_lines_removed > 0 && !args.no_exit_status && !args.benchmark {
+        EXIT_LINES_REMOVED
+    } else {
+        0
+    }
+}
+
+/// Parse command-line arguments and return the parsed args
+#[cfg(not(test))]
+fn parse_args() -> Result<Args, SieveError> {
+    parse_args_with_config(std::env::args_os(), Path::new(CONFIG_FILE_NAME))
+}
+
+/// Test-friendly version of argument parsing
+#[cfg(test)]
+fn parse_args() -> Result<Args, SieveError> {
+    // In actual usage, this function is replaced by the one above
+    unreachable!("This function should only be used in tests")
+}
+
+/// Parse arguments, filling in anything they leave out from the file given with
+/// `--config`, or from `default_config` if that exists
+pub(crate) fn parse_args_with_config<I, T>(
+    itr: I,
+    default_config: &Path,
+) -> Result<Args, SieveError>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = Args::command().get_matches_from(itr);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config_path = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => default_config
+            .exists()
+            .then(|| default_config.to_path_buf()),
+    };
+    if let Some(path) = config_path {
+        Config::load(&path)?.apply(&mut args, &matches)?;
+    }
+    Ok(args)
+}
+
+/// Parse arguments from a vec of strings (for testing)
+#[cfg(test)]
+pub(crate) fn parse_args_from(args: Vec<&str>) -> Args {
+    Args::parse_from(args)
+}
+
+/// Write the completion script for `shell` to `out`
+pub(crate) fn write_completions(shell: Shell, out: &mut dyn Write) -> Result<(), SieveError> {
+    let mut command = Args::command();
+    command.set_bin_name("sieve");
+    command.build();
+    shell.try_generate(&command, out)?;
+    Ok(())
+}
+
+/// Setup logging based on the command-line arguments
+pub(crate) fn setup_logging(
+    log_output: &LogOutput,
+    log_format: &LogFormat,
+) -> Result<Option<String>, SieveError> {
+    let log_file_name = format!("{}-sieve.log", Local::now().format("%Y-%m-%d-%H-%M-%S"));
+
+    match log_output {
+        LogOutput::File => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_file_name)?;
+            // Without an explicit level env_logger only passes errors through
+            let logger = logger_builder(log_format)
+                .filter_level(LevelFilter::Info)
+                .target(env_logger::Target::Pipe(Box::new(file)))
+                .build();
+            set_max_level(LevelFilter::Info);
+            log::set_boxed_logger(Box::new(logger)).unwrap();
+            Ok(Some(log_file_name))
+        }
+        LogOutput::Stdout => {
+            logger_builder(log_format).parse_default_env().init();
+            Ok(None)
+        }
+    }
+}
+
+/// A logger builder that writes records in the given format
+pub(crate) fn logger_builder(log_format: &LogFormat) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    if *log_format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_log_record(record)));
+    }
+    builder
+}
+
+/// A log record as a JSON object; `file` and `line` are null when unknown
+fn json_log_record(record: &Record) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": Local::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "file": record.file(),
+        "line": record.line(),
+    })
+}
+
+/// The locale named by the environment, checking the variables in the order that
+/// decides how numbers are formatted. `var` looks a variable up, like `std::env::var`.
+/// A value such as `de_CH.UTF-8` is tried as `de-CH` and then as `de`; anything
+/// `num_format` doesn't know, such as `C`, gives `None`.
+pub(crate) fn locale_from_env(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let value = ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .into_iter()
+        .filter_map(var)
+        .find(|value| !value.is_empty())?;
+    let name = value.split(['.', '@']).next().unwrap_or_default();
+    let language = name.split('_').next().unwrap_or_default();
+    [name.replace('_', "-"), language.to_string()]
+        .into_iter()
+        .find(|candidate| candidate.parse::<Locale>().is_ok())
+}
+
+/// With `--strict`, a locale that can't be used is an error up front rather than a
+/// warning when the summary is printed
+pub(crate) fn check_locale(args: &Args) -> Result<(), SieveError> {
+    let locale = args.locale();
+    if args.strict && locale.parse::<Locale>().is_err() {
+        return Err(SieveError::Strict(format!(
+            "Invalid locale string '{locale}'"
+        )));
+    }
+    Ok(())
+}
+
+/// Warn, or with `--strict` fail, when there's no pattern left to match once the empty
+/// ones are dropped, as then no line will be removed
+pub(crate) fn check_patterns(args: &Args) -> Result<(), SieveError> {
+    let match_kind = args.match_kind();
+    if args
+        .patterns
+        .iter()
+        .any(|pattern| !pattern.is_empty() || match_kind == MatchKind::Exact)
+    {
+        return Ok(());
+    }
+    let message = "No patterns to match, so no lines will be removed".to_string();
+    if args.strict {
+        return Err(SieveError::Strict(message));
+    }
+    warn!("{message}");
+    Ok(())
+}
+
+/// Get locale for number formatting
+pub(crate) fn get_locale(locale_str: &str) -> Locale {
+    if let Ok(locale) = locale_str.parse::<Locale>() {
+        locale
+    } else {
+        warn!("Invalid locale string '{locale_str}' provided. Defaulting to 'en'.",);
+        Locale::en
+    }
+}
+
+/// Write the end-of-run report in the requested format, or nothing in quiet mode
+pub(crate) fn report_summary(
+    out: &mut impl Write,
+    args: &Args,
+    summary: &RunSummary,
+) -> Result<(), SieveError> {
+    let locale = args.locale();
+    log_summary(log::logger(), summary, &args.mode(), &locale);
+    if args.quiet {
+        return Ok(());
+    }
+    if args.count {
+        return print_counts(out, summary, &args.mode());
+    }
+    if args.benchmark {
+        return Ok(print_benchmark(out, summary, &locale)?);
+    }
+    match args.format {
+        OutputFormat::Text => print_summary(out, summary, &args.mode(), &locale)?,
+        OutputFormat::Json => writeln!(out, "{}", json_summary(summary))?,
+    }
+    Ok(())
+}
+
+/// One `path:count` line per file, counting matching lines the way `grep -c` does
+pub(crate) fn print_counts(
+    out: &mut impl Write,
+    summary: &RunSummary,
+    mode: &Mode,
+) -> Result<(), SieveError> {
+    for (path, result) in &summary.file_results {
+        let matched = match mode {
+            Mode::Remove | Mode::Redact => result.removed,
+            Mode::Keep => result.read - result.removed,
+        };
+        writeln!(out, "{}:{matched}", path.display())?;
+    }
+    Ok(())
+}
+
+/// Report a `--benchmark` run: how fast the files went through overall, in bytes of
+/// input and lines, and how long single files took
+pub(crate) fn print_benchmark(
+    out: &mut impl Write,
+    summary: &RunSummary,
+    locale_str: &str,
+) -> std::io::Result<()> {
+    let locale = get_locale(locale_str);
+    let seconds = summary.elapsed_seconds.max(f64::EPSILON);
+    writeln!(
+        out,
+        "Benchmarked {} files ({}) in {:.2}s; nothing was changed.",
+        summary.files_processed.to_formatted_string(&locale),
+        HumanBytes(summary.total_bytes_before),
+        summary.elapsed_seconds,
+    )?;
+    writeln!(
+        out,
+        "Throughput: {:.1} MB/s, {} lines/s",
+        summary.total_bytes_before as f64 / 1_000_000.0 / seconds,
+        ((summary.total_lines_read as f64 / seconds) as u64).to_formatted_string(&locale),
+    )?;
+    if !summary.file_seconds.is_empty() {
+        let millis = |p| percentile(&summary.file_seconds, p) * 1000.0;
+        writeln!(
+            out,
+            "Per-file latency: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            millis(50.0),
+            millis(90.0),
+            millis(99.0),
+            millis(100.0),
+        )?;
+    }
+    if summary.files_failed > 0 {
+        writeln!(
+            out,
+            "Failed to process {} files.",
+            summary.files_failed.to_formatted_string(&locale)
+        )?;
+    }
+    Ok(())
+}
+
+/// The `p`th percentile of ascending `sorted` values, by nearest rank
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// The headline of the summary, e.g. "Removed 10 lines from a total of 100 lines read."
+fn summary_line(summary: &RunSummary, mode: &Mode, locale: &Locale) -> String {
+    let (action, count) = match mode {
+        Mode::Remove => ("Removed", summary.total_lines_removed),
+        Mode::Redact => ("Redacted", summary.total_lines_removed),
+        Mode::Keep => (
+            "Kept",
+            summary.total_lines_read - summary.total_lines_removed,
+        ),
+    };
+    format!(
+        "{action} {} lines from a total of {} lines read.",
+        count.to_formatted_string(locale),
+        summary.total_lines_read.to_formatted_string(locale),
+    )
+}
+
+/// How many files were processed, skipped and failed, e.g. "10 files processed, 2 skipped, 0 failed."
+fn files_line(summary: &RunSummary, locale: &Locale) -> String {
+    format!(
+        "{} files processed, {} skipped, {} failed.",
+        summary.files_processed.to_formatted_string(locale),
+        summary.files_skipped.to_formatted_string(locale),
+        summary.files_failed.to_formatted_string(locale),
+    )
+}
+
+/// Record the summary in the log too, so the outcome is kept with the run's log file
+/// even when stdout isn't captured
+pub(crate) fn log_summary(logger: &dyn Log, summary: &RunSummary, mode: &Mode, locale_str: &str) {
+    let locale = get_locale(locale_str);
+    logger.log(
+        &Record::builder()
+            .level(Level::Info)
+            .target(module_path!())
+            .args(format_args!(
+                "{} {}",
+                summary_line(summary, mode, &locale),
+                files_line(summary, &locale),
+            ))
+            .build(),
+    );
+}
+
+/// Print summary of processing results
+pub(crate) fn print_summary(
+    out: &mut impl Write,
+    summary: &RunSummary,
+    mode: &Mode,
+    locale_str: &str,
+) -> std::io::Result<()> {
+    let locale = get_locale(locale_str);
+    writeln!(out, "{}", summary_line(summary, mode, &locale))?;
+    if summary.total_lines_collapsed > 0 {
+        writeln!(
+            out,
+            "Collapsed {} repeated lines.",
+            summary.total_lines_collapsed.to_formatted_string(&locale)
+        )?;
+    }
+    writeln!(out, "{}", files_line(summary, &locale))?;
+    if summary.total_bytes_before > 0 {
+        let before = summary.total_bytes_before;
+        let after = summary.total_bytes_after;
+        let change = if after <= before {
+            format!(
+                "saving {} bytes",
+                (before - after).to_formatted_string(&locale)
+            )
+        } else {
+            format!(
+                "growing by {} bytes",
+                (after - before).to_formatted_string(&locale)
+            )
+        };
+        writeln!(
+            out,
+            "Files went from {} bytes to {} bytes, {change}.",
+            before.to_formatted_string(&locale),
+            after.to_formatted_string(&locale),
+        )?;
+    }
+    if !summary.pattern_hits.is_empty() {
+        let mut pattern_hits: Vec<_> = summary.pattern_hits.iter().collect();
+        pattern_hits.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+        writeln!(out, "Lines matched per pattern:")?;
+        for (pattern, hits) in pattern_hits {
+            let count = hits.to_formatted_string(&locale);
+            if *hits == 0 {
+                writeln!(
+                    out,
+                    "  {count:>10}  {pattern}  (never matched, potentially removable)"
+                )?;
+            } else {
+                writeln!(out, "  {count:>10}  {pattern}")?;
+            }
+        }
+    }
+    if !summary.removal_histogram.is_empty() {
+        print_histogram(out, &summary.removal_histogram, &locale)?;
+    }
+    if summary.files_failed > 0 {
+        writeln!(
+            out,
+            "Failed to process {} files:",
+            summary.files_failed.to_formatted_string(&locale),
+        )?;
+        for (path, error) in &summary.failures {
+            writeln!(out, "  {}: {error}", path.display())?;
+        }
+    }
+    Ok(())
+}
+
+/// Widest bar in the removal histogram, in characters
+const HISTOGRAM_WIDTH: u128 = 40;
+
+/// List the files in each bucket of [`RunSummary::removal_histogram`], with a bar
+/// scaled to the fullest bucket
+fn print_histogram(out: &mut impl Write, buckets: &[u64], locale: &Locale) -> std::io::Result<()> {
+    let fullest = buckets.iter().copied().max().unwrap_or(0).max(1);
+    writeln!(out, "Files by lines removed:")?;
+    for (bucket, &files) in buckets.iter().enumerate() {
+        let range = match bucket {
+            0 => "0".to_string(),
+            1 => "1-10".to_string(),
+            // 10^20 is past u64, though no file gets near that bucket
+            bucket => format!(
+                "{}-{}",
+                (10_u128.pow(bucket as u32 - 1) + 1).to_formatted_string(locale),
+                10_u128.pow(bucket as u32).to_formatted_string(locale),
+            ),
+        };
+        let bar = (u128::from(files) * HISTOGRAM_WIDTH).div_ceil(u128::from(fullest));
+        let bar = "#".repeat(bar as usize);
+        writeln!(
+            out,
+            "  {range:>15}  {:>10}  {bar}",
+            files.to_formatted_string(locale)
+        )?;
+    }
+    Ok(())
+}
+
+/// The bar drawn while files are processed. It's hidden when quiet, and when stderr
+/// isn't a terminal, where its control sequences would garble redirected output,
+/// unless `--progress` asks for it regardless.
+pub(crate) fn progress_bar(args: &Args, stderr_is_terminal: bool) -> ProgressBar {
+    if args.quiet || !(stderr_is_terminal || args.progress) {
+        return ProgressBar::hidden();
+    }
+    let progress = if stderr_is_terminal {
+        ProgressBar::no_length()
+    } else {
+        // The plain stderr target checks for a terminal itself and would draw nothing
+        let target = ProgressDrawTarget::term_like(Box::new(Term::stderr()));
+        ProgressBar::with_draw_target(None, target)
+    };
+
+    // Adapt the bar's width to the terminal
+    let term_width = match term_size::dimensions() {
+        Some((width, _)) => width.max(80),
+        None => 80,
+    };
+    let bar_width = (term_width / 2).clamp(40, 100);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template(&format!(
+                "[{{elapsed_precise}}] {{bar:{bar_width}.cyan/blue}} {{bytes}}/{{total_bytes}} ({{eta}})"
+            ))
+            .unwrap()
+            .progress_chars("##-"),
+    );
+    progress
+}
+
+impl ProgressSink for ProgressBar {
+    fn on_start(&self, total_size: u64) {
+        self.set_length(total_size);
+    }
+
+    fn on_progress(&self, bytes: u64) {
+        self.inc(bytes);
+    }
+
+    fn on_finish(&self, completed: bool) {
+        if completed {
+            self.finish_with_message("Done!");
+        } else {
+            self.abandon();
+        }
+    }
+}
+
+/// Directory that `--output-dir` mirrors: the root itself, or the directory of a file root
+pub(crate) fn mirror_root(root: &Path) -> &Path {
+    match root.parent() {
+        Some(parent) if root.is_file() => parent,
+        _ => root,
+    }
+}
+
+/// The first few removed lines of a run, kept for a look at what was dropped
+pub(crate) struct Samples {
+    limit: usize,
+    /// Lines offered so far, checked before locking so that once the samples are
+    /// full every later line costs a single atomic increment
+    offered: AtomicUsize,
+    lines: Mutex<Vec<String>>,
+}
+
+impl Samples {
+    pub(crate) fn new(limit: usize) -> Self {
+        Samples {
+            limit,
+            offered: AtomicUsize::new(0),
+            lines: Mutex::new(Vec::with_capacity(limit)),
+        }
+    }
+
+    /// Keep `line` if there's still room
+    pub(crate) fn record(&self, line: &str) {
+        if self.offered.fetch_add(1, Ordering::Relaxed) < self.limit {
+            self.lines.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    pub(crate) fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+}
+
+/// List sampled removed lines after the summary
+pub(crate) fn print_samples(out: &mut impl Write, samples: &[String]) -> std::io::Result<()> {
+    if samples.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "Sample of removed lines:")?;
+    for line in samples {
+        writeln!(out, "  {line}")?;
+    }
+    Ok(())
+}
+
+/// Patterns that matched no line in the whole run, in the order they were given
+pub(crate) fn unused_patterns(summary: &RunSummary) -> Vec<&str> {
+    summary
+        .pattern_hits
+        .iter()
+        .filter(|(_, hits)| *hits == 0)
+        .map(|(pattern, _)| pattern.as_str())
+        .collect()
+}
+
+/// Render the run summary as a single-line JSON object
+pub(crate) fn json_summary(summary: &RunSummary) -> String {
+    serde_json::to_string(summary).expect("summary is always serializable")
+}
+
+/// The run's counts in the Prometheus text exposition format
+pub(crate) fn metrics_text(summary: &RunSummary) -> String {
+    let metrics: [(&str, &str, &str, String); 6] = [
+        (
+            "sieve_lines_read_total",
+            "counter",
+            "Lines read in the last run.",
+            summary.total_lines_read.to_string(),
+        ),
+        (
+            "sieve_lines_removed_total",
+            "counter",
+            "Lines removed in the last run.",
+            summary.total_lines_removed.to_string(),
+        ),
+        (
+            "sieve_files_processed_total",
+            "counter",
+            "Files processed in the last run.",
+            summary.files_processed.to_string(),
+        ),
+        (
+            "sieve_files_skipped_total",
+            "counter",
+            "Files left out of the last run.",
+            summary.files_skipped.to_string(),
+        ),
+        (
+            "sieve_files_failed_total",
+            "counter",
+            "Files that failed in the last run.",
+            summary.files_failed.to_string(),
+        ),
+        (
+            "sieve_duration_seconds",
+            "gauge",
+            "How long the last run took.",
+            summary.elapsed_seconds.to_string(),
+        ),
+    ];
+    metrics
+        .iter()
+        .map(|(name, kind, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n")
+        })
+        .collect()
+}
+
+/// Write the metrics to `path`, through a temporary file renamed into place so a
+/// scrape never sees half a file
+pub(crate) fn write_metrics(path: &Path, summary: &RunSummary) -> Result<(), SieveError> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(metrics_text(summary).as_bytes())?;
+    file.persist(path).map_err(|e| SieveError::FileOpen {
+        path: path.display().to_string(),
+        source: e.error,
+    })?;
+    Ok(())
+}
+
+/// Remove empty log file if exists
+pub(crate) fn cleanup_empty_log_file(log_file_name: &str) -> Result<(), SieveError> {
+    let metadata = std::fs::metadata(log_file_name)?;
+    if metadata.len() == 0 {
+        std::fs::remove_file(log_file_name)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! Defaults read from a `sieve.toml` file, for flags that would otherwise be repeated on every run
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, ValueEnum};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use super::{
+    Args, LogFormat, LogOutput, OutputFormat, parse_buffer_size, parse_delimiter, parse_duration,
+    parse_ratio, parse_size, parse_threads,
+};
+use crate::{Codec, Mode, SieveError};
+
+/// Settings a config file can provide. Keys are the long flag names (`max-depth = 2`),
+/// and sizes, durations, enums and the delimiter are written as they would be on the
+/// command line. Flags that only make sense for a single run, such as `--dry-run` or
+/// `--checkpoint`, aren't accepted.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    patterns: Option<Vec<String>>,
+    mode: Option<String>,
+    redact: Option<String>,
+    regex: Option<bool>,
+    starts_with: Option<bool>,
+    ends_with: Option<bool>,
+    exact: Option<bool>,
+    match_all: Option<bool>,
+    trim: Option<bool>,
+    decode_escapes: Option<bool>,
+    keep: Option<Vec<String>>,
+    tar: Option<bool>,
+    skip_lines: Option<u64>,
+    keep_header: Option<u64>,
+    dedup_consecutive: Option<bool>,
+    field: Option<usize>,
+    field_delimiter: Option<String>,
+    drop_missing_field: Option<bool>,
+    backup: Option<String>,
+    keep_existing_backup: Option<bool>,
+    output_dir: Option<PathBuf>,
+    removed_output: Option<PathBuf>,
+    extensions: Option<Vec<String>>,
+    detect_magic: Option<bool>,
+    delimiter: Option<String>,
+    delete_emptied: Option<bool>,
+    no_empty: Option<bool>,
+    max_removal_ratio: Option<f64>,
+    binary_safe: Option<bool>,
+    compression_level: Option<u32>,
+    output_codec: Option<String>,
+    buffer_size: Option<String>,
+    parallel_above: Option<String>,
+    parallel_gzip: Option<bool>,
+    max_throughput: Option<String>,
+    mmap: Option<bool>,
+    verify: Option<bool>,
+    force: Option<bool>,
+    fsync: Option<bool>,
+    keep_temp_on_error: Option<bool>,
+    preserve_metadata: Option<bool>,
+    max_depth: Option<usize>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    min_size: Option<String>,
+    max_size: Option<String>,
+    modified_since: Option<String>,
+    modified_before: Option<String>,
+    follow_symlinks: Option<bool>,
+    skip_processed: Option<bool>,
+    threads: Option<usize>,
+    sequential: Option<bool>,
+    log_output: Option<String>,
+    log_format: Option<String>,
+    locale: Option<String>,
+    format: Option<String>,
+    stats: Option<bool>,
+    histogram: Option<bool>,
+    show_samples: Option<usize>,
+    warn_unused_patterns: Option<bool>,
+    progress: Option<bool>,
+    prompt_above: Option<String>,
+    metrics_file: Option<PathBuf>,
+    lock_file: Option<PathBuf>,
+    wait_for_lock: Option<bool>,
+    quiet: Option<bool>,
+    fail_fast: Option<bool>,
+    strict: Option<bool>,
+    no_exit_status: Option<bool>,
+}
+
+impl Config {
+    /// Read and parse the config file at `path`
+    pub(crate) fn load(path: &Path) -> Result<Config, SieveError> {
+        let text = std::fs::read_to_string(path).map_err(|e| SieveError::FileOpen {
+            path: path.display().to_string(),
+            source: e,
+        })?;
+        toml::from_str(&text)
+            .map_err(|e| SieveError::InvalidOptions(format!("{}: {e}", path.display())))
+    }
+
+    /// Fill in `args` from the config. Anything given on the command line wins, including
+    /// flags that conflict with a config value, so `--exact` overrides `regex = true`.
+    /// Two matchers both set in the config are rejected, as they would be as flags.
+    pub(crate) fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<(), SieveError> {
+        let config = self;
+        let command = Args::command();
+        let on_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let overridden = |id: &str| {
+            let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id) else {
+                return false;
+            };
+            on_command_line(id)
+                || command
+                    .get_arguments()
+                    .filter(|other| on_command_line(other.get_id().as_str()))
+                    .any(|other| {
+                        command.get_arg_conflicts_with(arg).contains(&other)
+                            || command.get_arg_conflicts_with(other).contains(&arg)
+                    })
+        };
+
+        macro_rules! apply {
+            ($field:ident) => {
+                apply!($field = config.$field)
+            };
+            ($field:ident = $value:expr) => {
+                if let Some(value) = $value
+                    && !overridden(stringify!($field))
+                {
+                    args.$field = value.into();
+                }
+            };
+        }
+
+        apply!(patterns);
+        apply!(mode = parse_value("mode", config.mode, |v| Mode::from_str(v, true))?);
+        apply!(redact);
+        apply!(regex);
+        apply!(starts_with);
+        apply!(ends_with);
+        apply!(exact);
+        apply!(match_all);
+        apply!(trim);
+        apply!(decode_escapes);
+        apply!(keep);
+        apply!(tar);
+        apply!(skip_lines);
+        apply!(keep_header);
+        apply!(dedup_consecutive);
+        apply!(field);
+        apply!(
+            field_delimiter =
+                parse_value("field-delimiter", config.field_delimiter, parse_delimiter)?
+        );
+        apply!(drop_missing_field);
+        apply!(backup);
+        apply!(keep_existing_backup);
+        apply!(output_dir);
+        apply!(removed_output);
+        apply!(extensions);
+        apply!(detect_magic);
+        apply!(delimiter = parse_value("delimiter", config.delimiter, parse_delimiter)?);
+        apply!(delete_emptied);
+        apply!(no_empty);
+        apply!(
+            max_removal_ratio = parse_value(
+                "max-removal-ratio",
+                config.max_removal_ratio.map(|ratio| ratio.to_string()),
+                parse_ratio
+            )?
+        );
+        apply!(binary_safe);
+        apply!(compression_level);
+        apply!(
+            output_codec = parse_value("output-codec", config.output_codec, |v| Codec::from_str(
+                v, true
+            ))?
+        );
+        apply!(buffer_size = parse_value("buffer-size", config.buffer_size, parse_buffer_size)?);
+        apply!(parallel_above = parse_value("parallel-above", config.parallel_above, parse_size)?);
+        apply!(parallel_gzip);
+        apply!(max_throughput = parse_value("max-throughput", config.max_throughput, parse_size)?);
+        apply!(mmap);
+        apply!(verify);
+        apply!(force);
+        apply!(fsync);
+        apply!(keep_temp_on_error);
+        apply!(preserve_metadata);
+        apply!(max_depth);
+        apply!(include);
+        apply!(exclude);
+        apply!(min_size = parse_value("min-size", config.min_size, parse_size)?);
+        apply!(max_size = parse_value("max-size", config.max_size, parse_size)?);
+        apply!(
+            modified_since = parse_value("modified-since", config.modified_since, parse_duration)?
+        );
+        apply!(
+            modified_before =
+                parse_value("modified-before", config.modified_before, parse_duration)?
+        );
+        apply!(follow_symlinks);
+        apply!(skip_processed);
+        apply!(
+            threads = parse_value(
+                "threads",
+                config.threads.map(|threads| threads.to_string()),
+                parse_threads
+            )?
+        );
+        apply!(sequential);
+        apply!(
+            log_output = parse_value("log-output", config.log_output, |v| {
+                LogOutput::from_str(v, true)
+            })?
+        );
+        apply!(
+            log_format = parse_value("log-format", config.log_format, |v| {
+                LogFormat::from_str(v, true)
+            })?
+        );
+        apply!(locale);
+        apply!(format = parse_value("format", config.format, |v| OutputFormat::from_str(v, true))?);
+        apply!(stats);
+        apply!(histogram);
+        apply!(show_samples);
+        apply!(warn_unused_patterns);
+        apply!(progress);
+        apply!(prompt_above = parse_value("prompt-above", config.prompt_above, parse_size)?);
+        apply!(metrics_file);
+        apply!(lock_file);
+        apply!(wait_for_lock);
+        apply!(quiet);
+        apply!(fail_fast);
+        apply!(strict);
+        apply!(no_exit_status);
+
+        // Clap only catches these on the command line
+        let matchers: Vec<&str> = [
+            ("regex", args.regex),
+            ("starts-with", args.starts_with),
+            ("ends-with", args.ends_with),
+            ("exact", args.exact),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(key, _)| key)
+        .collect();
+        if matchers.len() > 1 {
+            return Err(SieveError::InvalidOptions(format!(
+                "config {} can't be used together",
+                matchers.join(" and ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a config value with the same parser its flag uses
+fn parse_value<T>(
+    key: &str,
+    value: Option<String>,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, SieveError> {
+    value
+        .map(|value| {
+            parse(&value).map_err(|e| SieveError::InvalidOptions(format!("config {key}: {e}")))
+        })
+        .transpose()
+}
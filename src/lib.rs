@@ -0,0 +1,3101 @@
+//! Remove (or keep) lines matching patterns in compressed log files, rewriting each
+//! file in place in its original format.
+//!
+//! The `sieve` binary is a thin wrapper around [`cli::run`]; the same filtering is
+//! available to other programs through [`filter_lines`] for a single file, or
+//! [`gather_gz_files`] and [`process_files`] for a whole tree.
+//!
+//! ```
+//! use std::io::{Read, Write};
+//!
+//! use flate2::Compression;
+//! use flate2::read::GzDecoder;
+//! use flate2::write::GzEncoder;
+//! use sieve::{Matcher, SieveOptions, filter_lines};
+//!
+//! let dir = tempfile::tempdir()?;
+//! let path = dir.path().join("app.log.gz");
+//! let mut encoder = GzEncoder::new(std::fs::File::create(&path)?, Compression::default());
+//! encoder.write_all(b"INFO started\nDEBUG noisy\nINFO done\n")?;
+//! encoder.finish()?;
+//!
+//! let options = SieveOptions::builder(["DEBUG"]).build()?;
+//! let matcher = Matcher::new(&options.patterns, options.match_kind)?;
+//! let result = filter_lines(&path, &matcher, &options)?;
+//! assert_eq!((result.read, result.removed), (3, 1));
+//!
+//! let mut content = String::new();
+//! GzDecoder::new(std::fs::File::open(&path)?).read_to_string(&mut content)?;
+//! assert_eq!(content, "INFO started\nINFO done\n");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+#[cfg(feature = "aho-corasick")]
+use aho_corasick::AhoCorasick;
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use clap::ValueEnum;
+use filetime::FileTime;
+use flate2::read::MultiGzDecoder;
+use flate2::{Compress, Compression, Crc, FlushCompress, GzBuilder, Status};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{debug, error, info, warn};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::fs::{File, OpenOptions, TryLockError, copy};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::NamedTempFile;
+use thiserror::Error;
+use walkdir::WalkDir;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+pub mod cli;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Error, Debug)]
+pub enum SieveError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to open file {path}: {source}")]
+    FileOpen {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to read line in {path}: {source}")]
+    LineRead {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid pattern {pattern}: {source}")]
+    InvalidPattern {
+        pattern: String,
+        source: regex::Error,
+    },
+
+    #[error("Invalid glob {glob}: {source}")]
+    InvalidGlob {
+        glob: String,
+        source: globset::Error,
+    },
+
+    #[error("Invalid options: {0}")]
+    InvalidOptions(String),
+
+    #[error("Backup file already exists: {0}")]
+    BackupExists(String),
+
+    #[error("Failed to process file: {0}")]
+    Processing(String),
+
+    #[error(
+        "Refusing to drop {dropped} of {read} lines from {path}: over the maximum removal ratio of {max_ratio}"
+    )]
+    RemovalRatioExceeded {
+        path: String,
+        dropped: u64,
+        read: u64,
+        max_ratio: f64,
+    },
+
+    #[error("Failed to process {path}: {source}")]
+    FileFailed {
+        path: String,
+        source: Box<SieveError>,
+    },
+
+    #[error("Interrupted before all files were processed")]
+    Cancelled,
+
+    #[error("Another run holds the lock on {0}")]
+    Locked(String),
+
+    #[error("{0} (failing because of --strict)")]
+    Strict(String),
+
+    #[error("Thread pool error: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+}
+
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum Mode {
+    #[default]
+    Remove,
+    Keep,
+    /// Keep every line but replace each match with [`SieveOptions::replacement`];
+    /// chosen with `--redact` rather than `--mode`
+    #[value(skip)]
+    Redact,
+}
+
+/// Byte that terminates each record, newline unless `--delimiter` says otherwise
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delimiter(pub u8);
+
+impl Default for Delimiter {
+    fn default() -> Self {
+        Delimiter(b'\n')
+    }
+}
+
+/// Outcome of a run, as reported by `--format json`
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub total_lines_read: u64,
+    /// Lines left out of rewritten files, whichever the mode
+    pub total_lines_removed: u64,
+    /// Repeated lines left out by [`SieveOptions::dedup_consecutive`], on top of those removed
+    pub total_lines_collapsed: u64,
+    /// On-disk size of the processed files before and after filtering
+    pub total_bytes_before: u64,
+    pub total_bytes_after: u64,
+    pub files_processed: u64,
+    /// Files found but left out before processing; [`process_files`] never sets this,
+    /// it's for the caller to fill in, e.g. from [`Gathered::skipped`]
+    pub files_skipped: u64,
+    pub files_failed: u64,
+    pub elapsed_seconds: f64,
+    /// Lines matched by each pattern, when [`SieveOptions::pattern_hits`] is set; just 0
+    /// or 1 each after [`SieveOptionsBuilder::matched_patterns`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pattern_hits: Vec<(String, u64)>,
+    /// Files processed, by how many lines they lost: 0, then 1-10, 11-100 and so on up
+    /// by powers of ten (see [`histogram_bucket`]). Empty unless [`RunOptions::histogram`]
+    /// is set, and cut off after the last bucket with any files.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removal_histogram: Vec<u64>,
+    /// Each file that failed and why
+    #[serde(skip)]
+    pub failures: Vec<(PathBuf, SieveError)>,
+    /// Each file processed and its result, sorted by path, when
+    /// [`RunOptions::file_results`] is set
+    #[serde(skip)]
+    pub file_results: Vec<(PathBuf, FileResult)>,
+    /// How long each file that succeeded took, fastest first, when
+    /// [`RunOptions::time_files`] is set
+    #[serde(skip)]
+    pub file_seconds: Vec<f64>,
+}
+
+/// Settings for a whole run rather than for individual files
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    /// Worker threads, at least 1; defaults to the number of logical CPUs
+    pub threads: Option<usize>,
+    /// Told how far through the run's total size each finished file takes it
+    pub progress: Option<Arc<dyn ProgressSink>>,
+    /// Stop at the first file that fails
+    pub fail_fast: bool,
+    /// Process one file at a time in path order on the calling thread, ignoring `threads`
+    pub sequential: bool,
+    /// Set (e.g. from a Ctrl-C handler) to stop starting new files
+    pub cancelled: Arc<AtomicBool>,
+    /// Unbuffered so every finished file is on disk before the next one starts
+    pub checkpoint: Option<Mutex<File>>,
+    /// Keep every file's result for [`RunSummary::file_results`]
+    pub file_results: bool,
+    /// Count files by lines removed for [`RunSummary::removal_histogram`]
+    pub histogram: bool,
+    /// Stamp each successfully processed file with [`PROCESSED_XATTR`]
+    pub mark_processed: bool,
+    /// Time each file for [`RunSummary::file_seconds`]
+    pub time_files: bool,
+}
+
+/// Buckets in [`RunSummary::removal_histogram`], enough for any `u64` count
+pub const HISTOGRAM_BUCKETS: usize = 21;
+
+/// The [`RunSummary::removal_histogram`] bucket for a file that lost `removed` lines:
+/// 0 for none, 1 for 1-10, and otherwise `n` for counts from 10^(n-1)+1 to 10^n
+pub fn histogram_bucket(removed: u64) -> usize {
+    match removed {
+        0 => 0,
+        removed => (removed - 1)
+            .checked_ilog10()
+            .map_or(1, |digits| digits as usize + 1),
+    }
+}
+
+/// Receives progress through a run of [`process_files`], e.g. to draw a progress bar.
+/// Called from whichever worker finished a file, so it must be shareable.
+pub trait ProgressSink: Send + Sync {
+    /// The run is starting on files totalling `total_size` bytes
+    fn on_start(&self, _total_size: u64) {}
+
+    /// Another `bytes` of the run's `total_size` have been processed
+    fn on_progress(&self, bytes: u64);
+
+    /// The run is over; `completed` is false if it was cut short by an error
+    fn on_finish(&self, _completed: bool) {}
+}
+
+impl std::fmt::Debug for dyn ProgressSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressSink(..)")
+    }
+}
+
+/// Settings controlling which files are collected for processing
+#[derive(Debug, Default)]
+pub struct GatherOptions {
+    /// Extensions treated as uncompressed text files
+    pub plain_extensions: Vec<String>,
+    /// Sniff files with unrecognized extensions for the gzip header
+    pub detect_magic: bool,
+    /// Subdirectory levels to descend; 0 keeps only the root's direct files
+    pub max_depth: Option<usize>,
+    /// Descend into symlinked directories
+    pub follow_symlinks: bool,
+    /// If set, only file names matching one of these globs are kept
+    pub include: Option<GlobSet>,
+    /// File names matching any of these globs are skipped, even if included
+    pub exclude: Option<GlobSet>,
+    /// Inclusive bounds on the on-disk file size in bytes
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Cutoffs on the file's modification time
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+    /// Skip files already stamped with [`PROCESSED_XATTR`]
+    pub skip_processed: bool,
+    /// Fail on unreadable paths and broken `.sieveignore` files instead of warning
+    pub strict: bool,
+}
+
+impl GatherOptions {
+    /// Whether the include/exclude globs allow this file name
+    fn is_selected(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        let included = self.include.as_ref().is_none_or(|set| set.is_match(name));
+        let excluded = self.exclude.as_ref().is_some_and(|set| set.is_match(name));
+        included && !excluded
+    }
+
+    /// Whether a file of this size falls within `--min-size`/`--max-size`
+    fn size_in_range(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    /// Whether a file modified at `modified` falls within the modification time window
+    fn modified_in_range(&self, modified: SystemTime) -> bool {
+        self.modified_after.is_none_or(|after| modified >= after)
+            && self.modified_before.is_none_or(|before| modified < before)
+    }
+}
+
+/// Name of the per-directory file listing archives that are never gathered
+pub const IGNORE_FILE_NAME: &str = ".sieveignore";
+
+/// The `.sieveignore` rules met while walking a tree, parsed once per directory,
+/// and what was wrong with any that couldn't be read
+#[derive(Default)]
+struct IgnoreRules {
+    rules: HashMap<PathBuf, Option<Gitignore>>,
+    problems: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// Whether `path` is ignored by the `.sieveignore` files between `root` and it.
+    /// As with `.gitignore`, patterns are relative to the file they're in and the
+    /// nearest file with a matching rule decides, so a `!pattern` can re-include.
+    fn is_ignored(&mut self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            let problems = &mut self.problems;
+            let rules = self.rules.entry(dir.to_path_buf()).or_insert_with(|| {
+                let ignore_file = dir.join(IGNORE_FILE_NAME);
+                if !ignore_file.is_file() {
+                    return None;
+                }
+                let mut builder = GitignoreBuilder::new(dir);
+                if let Some(e) = builder.add(&ignore_file) {
+                    warn!("Problem reading {}: {e}", ignore_file.display());
+                    problems.push(format!("Problem reading {}: {e}", ignore_file.display()));
+                }
+                builder
+                    .build()
+                    .map_err(|e| {
+                        warn!("Ignoring {}: {e}", ignore_file.display());
+                        problems.push(format!("Ignoring {}: {e}", ignore_file.display()));
+                    })
+                    .ok()
+            });
+            if let Some(rules) = rules {
+                let matched = rules.matched(path, is_dir);
+                if !matched.is_none() {
+                    return matched.is_ignore();
+                }
+            }
+            if dir == root {
+                break;
+            }
+        }
+        false
+    }
+}
+
+/// Compile a list of globs into one set, or `None` if the list is empty
+pub fn build_glob_set(globs: &[String]) -> Result<Option<GlobSet>, SieveError> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).map_err(|e| SieveError::InvalidGlob {
+            glob: glob.clone(),
+            source: e,
+        })?);
+    }
+    let set = builder.build().map_err(|e| SieveError::InvalidGlob {
+        glob: globs.join(","),
+        source: e,
+    })?;
+    Ok(Some(set))
+}
+
+/// A delimited field of each line, such as the third column of a TSV file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Field {
+    /// Position of the field, counting from 1
+    pub number: usize,
+    /// Byte separating one field from the next
+    pub separator: Delimiter,
+}
+
+impl Field {
+    /// Where the field lies within `line`, or `None` if the line has too few fields
+    fn range(&self, line: &str) -> Option<std::ops::Range<usize>> {
+        let separator = char::from(self.separator.0);
+        let mut start = 0;
+        for _ in 1..self.number {
+            start += line[start..].find(separator)? + 1;
+        }
+        let end = line[start..]
+            .find(separator)
+            .map_or(line.len(), |len| start + len);
+        Some(start..end)
+    }
+}
+
+/// `range` of `line` narrowed to leave out the whitespace at either end
+fn trimmed_range(line: &str, range: std::ops::Range<usize>) -> std::ops::Range<usize> {
+    let text = &line[range.clone()];
+    let start = range.end - text.trim_start().len();
+    let end = range.start + text.trim_end().len();
+    start..end.max(start)
+}
+
+/// Settings applied to every file processed in a run
+#[derive(Debug, Default)]
+pub struct SieveOptions {
+    /// Patterns a line is checked against
+    pub patterns: Vec<String>,
+    /// How each pattern is compared against a line
+    pub match_kind: MatchKind,
+    /// Whether matching lines are removed, are the only ones kept, or are redacted
+    pub mode: Mode,
+    /// A line only matches if it matches every pattern, rather than any of them
+    pub match_all: bool,
+    /// Match against each line (or field) with the whitespace around it trimmed off;
+    /// kept lines are still written as they were
+    pub trim: bool,
+    /// Match patterns against just this field of each line instead of the whole line
+    pub field: Option<Field>,
+    /// Drop lines with too few fields to have [`field`](Self::field) instead of keeping them
+    pub drop_missing_field: bool,
+    /// Sieve the text files inside `.tar.*` archives instead of the archives' raw lines
+    pub tar: bool,
+    /// Pass this many lines at the start of each file through without counting them,
+    /// as if they weren't part of the file; [`keep_header`](Self::keep_header) applies
+    /// to the lines that follow
+    pub skip_lines: u64,
+    /// Pass this many lines at the start of each file (after any skipped ones) through
+    /// untouched; unlike skipped lines, they count as read
+    pub keep_header: u64,
+    /// Write only the first of each run of identical kept lines, like `uniq`. Dropped
+    /// lines are invisible to it, so a run continues past them; a redacted line ends it.
+    pub dedup_consecutive: bool,
+    /// Lines matching any of these patterns are never dropped, whatever the mode;
+    /// compiled with the same [`MatchKind`] as `patterns` and checked against the same
+    /// [`field`](Self::field), so a line missing it isn't protected
+    pub keep_matcher: Option<Matcher>,
+    /// Text that replaces each match in [`Mode::Redact`]; a template with `$1`/`${name}`
+    /// capture group references when matching regexes
+    pub replacement: String,
+    /// Count what would be filtered but leave every file untouched
+    pub dry_run: bool,
+    /// Skip the writer entirely, not just the file it would produce
+    pub count_only: bool,
+    /// Extensions of uncompressed text files to handle as plain text
+    pub plain_extensions: Vec<String>,
+    /// Byte that ends each record
+    pub delimiter: Delimiter,
+    /// Delete files that would be left with no lines
+    pub delete_emptied: bool,
+    /// Leave files that would be left with no lines untouched
+    pub no_empty: bool,
+    /// Fail a file, leaving it untouched, if more than this fraction of its lines would go
+    pub max_removal_ratio: Option<f64>,
+    /// Pass lines with invalid UTF-8 through untouched rather than erroring
+    pub binary_safe: bool,
+    /// Compression level for rewritten files; `None` uses each format's default
+    pub compression_level: Option<u32>,
+    /// Format for rewritten files; `None` keeps each file's own. A file changing format
+    /// is renamed to match, and the original goes once the new file is in place.
+    pub output_codec: Option<Codec>,
+    /// Capacity of the read and write buffers around each file; `None` uses 8 KiB
+    pub buffer_size: Option<usize>,
+    /// Memory-map files of at least [`MIN_MMAP_SIZE`] bytes instead of reading them
+    pub mmap: bool,
+    /// Experimental: filter files of at least this many bytes on several threads at once
+    pub parallel_above: Option<u64>,
+    /// Compress gzip output in blocks on several threads at once (see [`ParallelGzEncoder`])
+    pub parallel_gzip: bool,
+    /// Filter and compress every file as usual but throw the output away, leaving
+    /// the files untouched, to time the whole pipeline
+    pub benchmark: bool,
+    /// Turn what would be warnings into errors: a file that would be emptied fails,
+    /// and [`process_files`] stops at the first file that fails or can't be
+    /// checkpointed or marked
+    pub strict: bool,
+    /// Decompress each rewritten file in full before it replaces the original, at the
+    /// cost of reading every output a second time
+    pub verify: bool,
+    /// Flush each rewritten file to disk before it replaces the original, and its
+    /// directory once it has, so a crash can't lose the new contents
+    pub fsync: bool,
+    /// When a file fails part way through, keep what was written of it so far next to
+    /// where the output would have gone (see [`PARTIAL_SUFFIX`]) instead of deleting it
+    pub keep_temp_on_error: bool,
+    /// Give rewritten files the original's timestamps; its permissions always carry over
+    pub preserve_metadata: bool,
+    /// Replace read-only originals instead of refusing them
+    pub force: bool,
+    /// Copy each original to its name plus this suffix before replacing it
+    pub backup_suffix: Option<String>,
+    /// Leave an existing backup alone instead of failing the file
+    pub keep_existing_backup: bool,
+    /// Write filtered files here instead of replacing the originals
+    pub output_dir: Option<OutputDir>,
+    /// Shared sidecar for removed lines; locked per line so workers never interleave
+    pub removed_output: Option<Mutex<BufWriter<File>>>,
+    /// Called with the text of every removed line, from whichever worker removed it
+    pub on_removed: Option<LineCallback>,
+    /// Where each file's removed lines are written as a unified diff, one file at a time
+    pub diff_output: Option<DiffOutput>,
+    /// Per-pattern match counters; `None` skips the bookkeeping
+    pub pattern_hits: Option<PatternHits>,
+    /// Limit on how fast all workers together read lines
+    pub throttle: Option<Throttle>,
+    /// Set from another thread to stop: files in progress are abandoned and left
+    /// untouched, no new ones are started, and [`process_files`] returns the counts
+    /// for the files already finished
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// Lines read between checks of [`SieveOptions::cancel`]
+const CANCEL_CHECK_LINES: u64 = 4096;
+
+/// Bytes read before a worker charges them to the [`Throttle`], so the shared lock
+/// is taken once per batch rather than once per line
+const THROTTLE_BATCH: usize = 64 * 1024;
+
+/// Paces the lines read by every worker to roughly `bytes_per_sec`, measured on the
+/// uncompressed data. It's a token bucket with no room for bursts: each batch of
+/// bytes books the next free slot of time and waits for it to come round.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// A throttle letting `bytes_per_sec` bytes through each second, starting now
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `bytes` more can be read without going over the limit
+    fn consume(&self, bytes: usize) {
+        let now = Instant::now();
+        let start = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let start = (*next_free).max(now);
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        std::thread::sleep(start.saturating_duration_since(now));
+    }
+}
+
+/// Number of lines each pattern has matched, indexed like [`SieveOptions::patterns`]
+/// and shared by every worker
+#[derive(Debug, Default)]
+pub struct PatternHits {
+    hits: Vec<AtomicU64>,
+    /// Only whether each pattern matched at all, so counting can stop once all have
+    matched_only: bool,
+    unmatched: AtomicUsize,
+}
+
+impl PatternHits {
+    /// Counters for `count` patterns, all starting at zero
+    pub fn new(count: usize) -> Self {
+        PatternHits {
+            hits: (0..count).map(|_| AtomicU64::new(0)).collect(),
+            matched_only: false,
+            unmatched: AtomicUsize::new(count),
+        }
+    }
+
+    /// Flags for `count` patterns, each going to 1 on its first match
+    pub fn matched_only(count: usize) -> Self {
+        PatternHits {
+            matched_only: true,
+            ..Self::new(count)
+        }
+    }
+
+    /// Count one more line matched by the pattern at `index`
+    fn record(&self, index: usize) {
+        if !self.matched_only {
+            self.hits[index].fetch_add(1, Ordering::Relaxed);
+        } else if self.hits[index].swap(1, Ordering::Relaxed) == 0 {
+            self.unmatched.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether there's nothing left to record: only flags are kept and every pattern
+    /// has matched
+    fn settled(&self) -> bool {
+        self.matched_only && self.unmatched.load(Ordering::Relaxed) == 0
+    }
+
+    /// The current count for each pattern
+    pub fn counts(&self) -> Vec<u64> {
+        self.hits
+            .iter()
+            .map(|hits| hits.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// A shareable per-line hook, such as [`SieveOptions::on_removed`]
+#[derive(Clone)]
+pub struct LineCallback(pub Arc<dyn Fn(&str) + Send + Sync>);
+
+impl std::fmt::Debug for LineCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LineCallback(..)")
+    }
+}
+
+/// Shared destination for [`SieveOptions::diff_output`]
+pub struct DiffOutput(pub Mutex<Box<dyn Write + Send>>);
+
+impl std::fmt::Debug for DiffOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DiffOutput(..)")
+    }
+}
+
+impl SieveOptions {
+    /// Start building options that match lines against `patterns`
+    pub fn builder<I, S>(patterns: I) -> SieveOptionsBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        SieveOptionsBuilder {
+            options: SieveOptions {
+                patterns: patterns.into_iter().map(Into::into).collect(),
+                ..Default::default()
+            },
+            keep_patterns: Vec::new(),
+        }
+    }
+
+    /// Whether [`cancel`](Self::cancel) has been set
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+}
+
+/// Builds [`SieveOptions`] one setting at a time, checking them in [`build`](Self::build).
+/// Each setter sets the `SieveOptions` field of the same name.
+#[derive(Debug)]
+pub struct SieveOptionsBuilder {
+    options: SieveOptions,
+    keep_patterns: Vec<String>,
+}
+
+impl SieveOptionsBuilder {
+    pub fn match_kind(mut self, match_kind: MatchKind) -> Self {
+        self.options.match_kind = match_kind;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.options.mode = mode;
+        self
+    }
+
+    pub fn match_all(mut self, match_all: bool) -> Self {
+        self.options.match_all = match_all;
+        self
+    }
+
+    pub fn trim(mut self, trim: bool) -> Self {
+        self.options.trim = trim;
+        self
+    }
+
+    pub fn field(mut self, field: impl Into<Option<Field>>) -> Self {
+        self.options.field = field.into();
+        self
+    }
+
+    pub fn drop_missing_field(mut self, drop_missing_field: bool) -> Self {
+        self.options.drop_missing_field = drop_missing_field;
+        self
+    }
+
+    pub fn tar(mut self, tar: bool) -> Self {
+        self.options.tar = tar;
+        self
+    }
+
+    pub fn skip_lines(mut self, skip_lines: u64) -> Self {
+        self.options.skip_lines = skip_lines;
+        self
+    }
+
+    pub fn keep_header(mut self, keep_header: u64) -> Self {
+        self.options.keep_header = keep_header;
+        self
+    }
+
+    pub fn dedup_consecutive(mut self, dedup_consecutive: bool) -> Self {
+        self.options.dedup_consecutive = dedup_consecutive;
+        self
+    }
+
+    /// Protect lines matching any of `patterns` from being dropped. They're compiled
+    /// into [`SieveOptions::keep_matcher`] by [`build`](Self::build).
+    pub fn keep_patterns<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.keep_patterns = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn replacement(mut self, replacement: impl Into<String>) -> Self {
+        self.options.replacement = replacement.into();
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    pub fn count_only(mut self, count_only: bool) -> Self {
+        self.options.count_only = count_only;
+        self
+    }
+
+    pub fn plain_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.options.plain_extensions = extensions;
+        self
+    }
+
+    pub fn delimiter(mut self, delimiter: Delimiter) -> Self {
+        self.options.delimiter = delimiter;
+        self
+    }
+
+    pub fn delete_emptied(mut self, delete_emptied: bool) -> Self {
+        self.options.delete_emptied = delete_emptied;
+        self
+    }
+
+    pub fn no_empty(mut self, no_empty: bool) -> Self {
+        self.options.no_empty = no_empty;
+        self
+    }
+
+    pub fn max_removal_ratio(mut self, ratio: impl Into<Option<f64>>) -> Self {
+        self.options.max_removal_ratio = ratio.into();
+        self
+    }
+
+    pub fn binary_safe(mut self, binary_safe: bool) -> Self {
+        self.options.binary_safe = binary_safe;
+        self
+    }
+
+    pub fn compression_level(mut self, level: impl Into<Option<u32>>) -> Self {
+        self.options.compression_level = level.into();
+        self
+    }
+
+    pub fn output_codec(mut self, codec: impl Into<Option<Codec>>) -> Self {
+        self.options.output_codec = codec.into();
+        self
+    }
+
+    pub fn buffer_size(mut self, bytes: impl Into<Option<usize>>) -> Self {
+        self.options.buffer_size = bytes.into();
+        self
+    }
+
+    pub fn parallel_above(mut self, bytes: impl Into<Option<u64>>) -> Self {
+        self.options.parallel_above = bytes.into();
+        self
+    }
+
+    pub fn parallel_gzip(mut self, enabled: bool) -> Self {
+        self.options.parallel_gzip = enabled;
+        self
+    }
+
+    pub fn benchmark(mut self, enabled: bool) -> Self {
+        self.options.benchmark = enabled;
+        self
+    }
+
+    pub fn strict(mut self, enabled: bool) -> Self {
+        self.options.strict = enabled;
+        self
+    }
+
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.options.mmap = mmap;
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.options.verify = verify;
+        self
+    }
+
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.options.fsync = fsync;
+        self
+    }
+
+    pub fn keep_temp_on_error(mut self, keep_temp_on_error: bool) -> Self {
+        self.options.keep_temp_on_error = keep_temp_on_error;
+        self
+    }
+
+    pub fn preserve_metadata(mut self, preserve_metadata: bool) -> Self {
+        self.options.preserve_metadata = preserve_metadata;
+        self
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.options.force = force;
+        self
+    }
+
+    pub fn backup_suffix(mut self, suffix: impl Into<Option<String>>) -> Self {
+        self.options.backup_suffix = suffix.into();
+        self
+    }
+
+    pub fn keep_existing_backup(mut self, keep_existing_backup: bool) -> Self {
+        self.options.keep_existing_backup = keep_existing_backup;
+        self
+    }
+
+    /// Mirror files from under `root` into `dir` instead of replacing them
+    pub fn output_dir(mut self, root: impl Into<PathBuf>, dir: impl Into<PathBuf>) -> Self {
+        self.options.output_dir = Some(OutputDir {
+            root: root.into(),
+            dir: dir.into(),
+        });
+        self
+    }
+
+    /// Append every removed line, prefixed with its source file and a tab, to `file`
+    pub fn removed_output(mut self, file: File) -> Self {
+        self.options.removed_output = Some(Mutex::new(BufWriter::new(file)));
+        self
+    }
+
+    /// Limit how many bytes of lines all workers together read per second
+    pub fn max_throughput(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.options.throttle = bytes_per_sec.map(Throttle::new);
+        self
+    }
+
+    /// Count the lines matched by each pattern, for [`RunSummary::pattern_hits`]
+    pub fn pattern_hits(mut self, enabled: bool) -> Self {
+        self.options.pattern_hits = enabled.then(|| PatternHits::new(self.options.patterns.len()));
+        self
+    }
+
+    /// Only note which patterns matched at all, so [`RunSummary::pattern_hits`] holds 0
+    /// or 1 for each; cheaper than counting, as matching stops once every pattern has.
+    /// Does nothing if [`pattern_hits`](Self::pattern_hits) is already enabled.
+    pub fn matched_patterns(mut self, enabled: bool) -> Self {
+        if enabled && self.options.pattern_hits.is_none() {
+            self.options.pattern_hits =
+                Some(PatternHits::matched_only(self.options.patterns.len()));
+        }
+        self
+    }
+
+    /// Call `callback` with the text of every removed line
+    pub fn on_removed(mut self, callback: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.options.on_removed = Some(LineCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Stop the run once `cancel` is set
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.options.cancel = Some(cancel);
+        self
+    }
+
+    /// Write a unified diff of each file's removed lines to `output`
+    pub fn diff_output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.options.diff_output = Some(DiffOutput(Mutex::new(Box::new(output))));
+        self
+    }
+
+    /// Finish building, rejecting settings that can't be used together
+    pub fn build(self) -> Result<SieveOptions, SieveError> {
+        let mut options = self.options;
+        options.patterns = normalize_patterns(options.patterns, options.match_kind);
+        // Sized for the patterns as given, which may have just lost some
+        if let Some(hits) = &options.pattern_hits {
+            let count = options.patterns.len();
+            options.pattern_hits = Some(if hits.matched_only {
+                PatternHits::matched_only(count)
+            } else {
+                PatternHits::new(count)
+            });
+        }
+        if !self.keep_patterns.is_empty() {
+            options.keep_matcher = Some(Matcher::new(&self.keep_patterns, options.match_kind)?);
+        }
+        if let Some(level) = options.compression_level
+            && level > 9
+        {
+            return Err(SieveError::InvalidOptions(format!(
+                "compression level {level} is not between 0 and 9"
+            )));
+        }
+        if let Some(size) = options.buffer_size
+            && size < MIN_BUFFER_SIZE
+        {
+            return Err(SieveError::InvalidOptions(format!(
+                "buffer size {size} is below the minimum of {MIN_BUFFER_SIZE} bytes"
+            )));
+        }
+        if let Some(ratio) = options.max_removal_ratio
+            && !(0.0..=1.0).contains(&ratio)
+        {
+            return Err(SieveError::InvalidOptions(format!(
+                "maximum removal ratio {ratio} is not between 0.0 and 1.0"
+            )));
+        }
+        if options
+            .throttle
+            .as_ref()
+            .is_some_and(|throttle| throttle.bytes_per_sec == 0)
+        {
+            return Err(SieveError::InvalidOptions(
+                "maximum throughput must be above zero".to_string(),
+            ));
+        }
+        if options.field.is_some_and(|field| field.number == 0) {
+            return Err(SieveError::InvalidOptions(
+                "fields are numbered from 1".to_string(),
+            ));
+        }
+        if options.delete_emptied && options.no_empty {
+            return Err(SieveError::InvalidOptions(
+                "emptied files can't be both deleted and left untouched".to_string(),
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Destination for filtered files when originals must not be overwritten
+#[derive(Debug)]
+pub struct OutputDir {
+    /// Scan root that source paths are made relative to
+    pub root: PathBuf,
+    /// Directory the relative structure is recreated under
+    pub dir: PathBuf,
+}
+
+impl OutputDir {
+    /// Path under the output directory that mirrors `file_path`'s position under the root.
+    /// A URL is placed by its host and path instead.
+    pub fn target_for(&self, file_path: &Path) -> Result<PathBuf, SieveError> {
+        if is_url(file_path) {
+            let relative = url_relative_path(&file_path.to_string_lossy()).ok_or_else(|| {
+                SieveError::Processing(format!("{} doesn't name a file", file_path.display()))
+            })?;
+            return Ok(self.dir.join(relative));
+        }
+        let relative = file_path.strip_prefix(&self.root).map_err(|_| {
+            SieveError::Processing(format!(
+                "{} is not under the scan root {}",
+                file_path.display(),
+                self.root.display()
+            ))
+        })?;
+        Ok(self.dir.join(relative))
+    }
+}
+
+/// Smallest read/write buffer accepted by [`SieveOptions::buffer_size`]
+pub const MIN_BUFFER_SIZE: usize = 1024;
+
+/// Read/write buffer capacity when none is configured, the same as `BufReader`'s
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Smallest file memory-mapped with [`SieveOptions::mmap`]; below this the mapping
+/// costs more than the reads it saves
+pub const MIN_MMAP_SIZE: u64 = 64 * 1024;
+
+/// Preset used when re-encoding xz files, matching the `xz` command's default
+const XZ_DEFAULT_LEVEL: u32 = 6;
+
+/// First two bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Longest record buffer a worker holds on to between files; one huge line
+/// shouldn't pin its memory for the rest of the run
+const MAX_REUSED_RECORD_CAPACITY: usize = 1 << 20;
+
+thread_local! {
+    /// Record buffer each worker reuses from file to file instead of growing a new one
+    static RECORD_BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// Format of a file, chosen from its extension
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+    #[value(name = "none", alias = "plain")]
+    Plain,
+}
+
+impl Codec {
+    /// Codec for the file's extension, or `None` if the file isn't supported.
+    /// `plain_extensions` lists extensions to handle as uncompressed text.
+    pub fn from_path(path: &Path, plain_extensions: &[String]) -> Option<Self> {
+        let extension = path.extension().and_then(|s| s.to_str())?;
+        match extension {
+            "gz" => Some(Codec::Gzip),
+            "zst" => Some(Codec::Zstd),
+            "bz2" => Some(Codec::Bzip2),
+            "xz" => Some(Codec::Xz),
+            _ if plain_extensions.iter().any(|ext| ext == extension) => Some(Codec::Plain),
+            _ => None,
+        }
+    }
+
+    /// Extension of files in this format, or `None` for uncompressed text
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Codec::Gzip => Some("gz"),
+            Codec::Zstd => Some("zst"),
+            Codec::Bzip2 => Some("bz2"),
+            Codec::Xz => Some("xz"),
+            Codec::Plain => None,
+        }
+    }
+
+    /// `path` renamed for this format instead of `from`, e.g. `app.log.gz` to
+    /// `app.log.zst`. Only an extension that names `from` is replaced, so a plain
+    /// `app.log` becomes `app.log.zst`.
+    fn rename(self, path: &Path, from: Codec) -> PathBuf {
+        if self == from {
+            return path.to_path_buf();
+        }
+        let base = match from.extension() {
+            Some(extension) if path.extension().is_some_and(|ext| ext == extension) => {
+                path.with_extension("")
+            }
+            _ => path.to_path_buf(),
+        };
+        match self.extension() {
+            Some(extension) => {
+                let mut name = base.into_os_string();
+                name.push(".");
+                name.push(extension);
+                PathBuf::from(name)
+            }
+            None => base,
+        }
+    }
+
+    /// Whether the file begins with the gzip magic bytes
+    fn has_gzip_magic(path: &Path) -> bool {
+        let mut magic = [0_u8; 2];
+        File::open(path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+            && magic == GZIP_MAGIC
+    }
+
+    /// Wrap a file's contents in the matching decompressing reader. Gzip files made by
+    /// appending several gzip members are read through to the last one.
+    fn reader<R: Read + 'static>(self, input: R) -> Result<Box<dyn Read>, SieveError> {
+        match self {
+            Codec::Gzip => Ok(Box::new(MultiGzDecoder::new(input))),
+            Codec::Zstd => Ok(Box::new(zstd::Decoder::new(input)?)),
+            Codec::Bzip2 => Ok(Box::new(BzDecoder::new(input))),
+            Codec::Xz => Ok(Box::new(XzDecoder::new(input))),
+            Codec::Plain => Ok(Box::new(input)),
+        }
+    }
+
+    /// Wrap a file in the matching compressing writer, finalized when dropped.
+    /// `level` (0-9) overrides each codec's default compression level.
+    fn writer<W: Write + 'static>(
+        self,
+        file: W,
+        level: Option<u32>,
+    ) -> Result<Box<dyn Write>, SieveError> {
+        let file = BufWriter::new(file);
+        match self {
+            Codec::Gzip => {
+                let compression = level.map_or_else(Compression::default, Compression::new);
+                // No timestamp or file name in the header, so sieving the same content
+                // always produces the same bytes
+                Ok(Box::new(GzBuilder::new().mtime(0).write(file, compression)))
+            }
+            Codec::Zstd => {
+                let level = level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |l| l as i32);
+                Ok(Box::new(zstd::Encoder::new(file, level)?.auto_finish()))
+            }
+            Codec::Bzip2 => {
+                // bzip2 block sizes start at 1
+                let compression = level.map_or_else(bzip2::Compression::default, |l| {
+                    bzip2::Compression::new(l.max(1))
+                });
+                Ok(Box::new(BzEncoder::new(file, compression)))
+            }
+            Codec::Xz => Ok(Box::new(XzEncoder::new(
+                file,
+                level.unwrap_or(XZ_DEFAULT_LEVEL),
+            ))),
+            Codec::Plain => Ok(Box::new(file)),
+        }
+    }
+}
+
+/// Input deflated as one block by [`ParallelGzEncoder`]
+const PARALLEL_GZIP_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Gzip writer that deflates its input in blocks on the current rayon pool, in the
+/// style of pigz. Each block is compressed on its own and ends on a byte boundary,
+/// so the blocks join into a single ordinary gzip member that any decoder reads.
+/// Compression is slightly worse than [`GzBuilder`]'s as no block sees the data
+/// before it. The workers are the pool's own, so a file only gets the threads that
+/// no other file is using. Finished when dropped.
+pub struct ParallelGzEncoder<W: Write> {
+    inner: W,
+    level: Compression,
+    /// Input waiting for a whole batch of blocks to build up
+    pending: Vec<u8>,
+    /// Checksum and length of all the input, for the trailer
+    crc: Crc,
+    header_written: bool,
+    finished: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(inner: W, level: Compression) -> Self {
+        ParallelGzEncoder {
+            inner,
+            level,
+            pending: Vec::new(),
+            crc: Crc::new(),
+            header_written: false,
+            finished: false,
+        }
+    }
+
+    /// Bytes of input collected before they're compressed, a block for every worker
+    fn batch_size() -> usize {
+        PARALLEL_GZIP_BLOCK_SIZE * rayon::current_num_threads()
+    }
+
+    /// Compress and write out everything pending, ending the deflate stream if `last`
+    fn write_pending(&mut self, last: bool) -> std::io::Result<()> {
+        if !self.header_written {
+            // The same header GzBuilder writes: no timestamp or name, unknown OS
+            let extra_flags = match self.level.level() {
+                9.. => 2,
+                0 | 1 => 4,
+                _ => 0,
+            };
+            self.inner
+                .write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, extra_flags, 255])?;
+            self.header_written = true;
+        }
+        let level = self.level;
+        let blocks = self.pending.len().div_ceil(PARALLEL_GZIP_BLOCK_SIZE);
+        let mut compressed = self
+            .pending
+            .par_chunks(PARALLEL_GZIP_BLOCK_SIZE)
+            .enumerate()
+            .map(|(index, block)| deflate_block(block, level, last && index + 1 == blocks))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // The stream still needs its final block when there's no input left for it
+        if last && blocks == 0 {
+            compressed.push(deflate_block(&[], level, true)?);
+        }
+        self.crc.update(&self.pending);
+        self.pending.clear();
+        for block in compressed {
+            self.inner.write_all(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Write the remaining input and the gzip trailer
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_pending(true)?;
+        self.inner.write_all(&self.crc.sum().to_le_bytes())?;
+        self.inner.write_all(&self.crc.amount().to_le_bytes())?;
+        self.finished = true;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= Self::batch_size() {
+            self.write_pending(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            self.write_pending(false)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ParallelGzEncoder<W> {
+    fn drop(&mut self) {
+        // Like flate2's encoders, an error this late has nowhere to go
+        let _ = self.finish();
+    }
+}
+
+impl<W: Write> std::fmt::Debug for ParallelGzEncoder<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ParallelGzEncoder(..)")
+    }
+}
+
+/// Raw deflate data for one block of a [`ParallelGzEncoder`]'s input. Every block but
+/// the last ends with a sync flush, leaving it byte-aligned for the next to follow.
+fn deflate_block(block: &[u8], level: Compression, last: bool) -> std::io::Result<Vec<u8>> {
+    let mut compress = Compress::new(level, false);
+    let flush = if last {
+        FlushCompress::Finish
+    } else {
+        FlushCompress::Sync
+    };
+    let mut out = Vec::with_capacity(block.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        let status = compress
+            .compress_vec(&block[consumed..], &mut out, flush)
+            .map_err(std::io::Error::other)?;
+        // A flush is complete once it leaves room to spare in the output
+        let done = if last {
+            status == Status::StreamEnd
+        } else {
+            compress.total_in() as usize == block.len() && out.len() < out.capacity()
+        };
+        if done {
+            return Ok(out);
+        }
+        out.reserve(out.capacity());
+    }
+}
+
+/// How a pattern is compared against a line
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MatchKind {
+    #[default]
+    Contains,
+    StartsWith,
+    EndsWith,
+    Exact,
+    Regex,
+}
+
+/// `patterns` without repeats, keeping the first of each, or empty patterns, which would
+/// match every line. An empty exact pattern only matches blank lines, so it's kept.
+fn normalize_patterns(patterns: Vec<String>, kind: MatchKind) -> Vec<String> {
+    let given = patterns.len();
+    let mut seen = HashSet::new();
+    let patterns: Vec<String> = patterns
+        .into_iter()
+        .filter(|pattern| seen.insert(pattern.clone()))
+        .filter(|pattern| {
+            let matches_everything = pattern.is_empty() && kind != MatchKind::Exact;
+            if matches_everything {
+                warn!("Ignoring the empty pattern, which would match every line");
+            }
+            !matches_everything
+        })
+        .collect();
+    debug!("Matching {} patterns ({given} given)", patterns.len());
+    patterns
+}
+
+/// Patterns prepared for matching, built once and shared across worker threads
+#[derive(Debug)]
+pub struct Matcher(Patterns);
+
+impl Matcher {
+    /// Build a matcher of the given kind from the raw patterns
+    pub fn new(patterns: &[String], kind: MatchKind) -> Result<Self, SieveError> {
+        Patterns::new(patterns, kind).map(Matcher)
+    }
+
+    /// Whether the line matches any of the patterns
+    pub fn is_match(&self, line: &str) -> bool {
+        self.0.is_match(line)
+    }
+
+    /// Whether the line matches every one of the patterns. A matcher with no patterns
+    /// matches nothing, as with [`is_match`](Self::is_match).
+    pub fn is_match_all(&self, line: &str) -> bool {
+        self.0.is_match_all(line)
+    }
+
+    /// Call `f` with the index of each pattern that matches the line, once per pattern.
+    /// Slower than [`is_match`](Self::is_match), which can stop at the first match.
+    pub fn for_each_match(&self, line: &str, f: impl FnMut(usize)) {
+        self.0.for_each_match(line, f)
+    }
+
+    /// The line with every match replaced by `replacement`. For regex patterns the
+    /// replacement is a template, where `$1` or `${name}` stands for a capture group
+    /// and `$$` for a literal `$`.
+    pub fn redact<'a>(&self, line: &'a str, replacement: &str) -> Cow<'a, str> {
+        self.0.redact(line, replacement)
+    }
+}
+
+/// Patterns compiled for one kind of matching
+#[derive(Debug)]
+enum Patterns {
+    /// The automaton, plus the patterns themselves for checking each one on its own
+    #[cfg(feature = "aho-corasick")]
+    Contains(AhoCorasick, Vec<String>),
+    #[cfg(not(feature = "aho-corasick"))]
+    Contains(Vec<String>),
+    StartsWith(Vec<String>),
+    EndsWith(Vec<String>),
+    Exact(Vec<String>),
+    Regex(Vec<Regex>),
+}
+
+impl Patterns {
+    /// Build a matcher of the given kind from the raw patterns
+    fn new(patterns: &[String], kind: MatchKind) -> Result<Self, SieveError> {
+        match kind {
+            MatchKind::Contains => Self::build_contains(patterns),
+            MatchKind::StartsWith => Ok(Patterns::StartsWith(patterns.to_vec())),
+            MatchKind::EndsWith => Ok(Patterns::EndsWith(patterns.to_vec())),
+            MatchKind::Exact => Ok(Patterns::Exact(patterns.to_vec())),
+            MatchKind::Regex => Self::compile_regexes(patterns),
+        }
+    }
+
+    /// Build a single automaton that checks every pattern in one pass over the line
+    #[cfg(feature = "aho-corasick")]
+    fn build_contains(patterns: &[String]) -> Result<Self, SieveError> {
+        // Leftmost-longest so redaction masks the longest overlapping pattern
+        let automaton = AhoCorasick::builder()
+            .match_kind(aho_corasick::MatchKind::LeftmostLongest)
+            .build(patterns)
+            .map_err(|e| SieveError::Processing(format!("Failed to build pattern matcher: {e}")))?;
+        Ok(Patterns::Contains(automaton, patterns.to_vec()))
+    }
+
+    /// Plain substring fallback for builds without the automaton
+    #[cfg(not(feature = "aho-corasick"))]
+    fn build_contains(patterns: &[String]) -> Result<Self, SieveError> {
+        Ok(Patterns::Contains(patterns.to_vec()))
+    }
+
+    /// Compile each pattern as a regex, reporting the first one that fails
+    fn compile_regexes(patterns: &[String]) -> Result<Self, SieveError> {
+        let compiled = patterns
+            .iter()
+            .map(|pat| {
+                Regex::new(pat).map_err(|e| SieveError::InvalidPattern {
+                    pattern: pat.clone(),
+                    source: e,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Patterns::Regex(compiled))
+    }
+
+    /// Whether the line matches any of the patterns
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            #[cfg(feature = "aho-corasick")]
+            Patterns::Contains(automaton, _) => automaton.is_match(line),
+            #[cfg(not(feature = "aho-corasick"))]
+            Patterns::Contains(patterns) => patterns.iter().any(|pat| line.contains(pat)),
+            Patterns::StartsWith(patterns) => patterns.iter().any(|pat| line.starts_with(pat)),
+            Patterns::EndsWith(patterns) => patterns.iter().any(|pat| line.ends_with(pat)),
+            // The line terminator has already been stripped
+            Patterns::Exact(patterns) => patterns.iter().any(|pat| line == pat),
+            Patterns::Regex(regexes) => regexes.iter().any(|re| re.is_match(line)),
+        }
+    }
+
+    /// Whether the line matches every one of the patterns (and there is at least one)
+    fn is_match_all(&self, line: &str) -> bool {
+        fn all<T>(patterns: &[T], is_match: impl Fn(&T) -> bool) -> bool {
+            !patterns.is_empty() && patterns.iter().all(is_match)
+        }
+        match self {
+            // Checked one by one, as the automaton skips patterns overlapping a longer match
+            #[cfg(feature = "aho-corasick")]
+            Patterns::Contains(_, patterns) => all(patterns, |pat| line.contains(pat.as_str())),
+            #[cfg(not(feature = "aho-corasick"))]
+            Patterns::Contains(patterns) => all(patterns, |pat| line.contains(pat.as_str())),
+            Patterns::StartsWith(patterns) => all(patterns, |pat| line.starts_with(pat.as_str())),
+            Patterns::EndsWith(patterns) => all(patterns, |pat| line.ends_with(pat.as_str())),
+            Patterns::Exact(patterns) => all(patterns, |pat| line == pat),
+            Patterns::Regex(regexes) => all(regexes, |re| re.is_match(line)),
+        }
+    }
+
+    /// Call `f` with the index of each pattern that matches the line
+    fn for_each_match(&self, line: &str, mut f: impl FnMut(usize)) {
+        match self {
+            #[cfg(feature = "aho-corasick")]
+            Patterns::Contains(automaton, patterns) => {
+                // The automaton skips patterns inside a longer match, so each is checked alone
+                if automaton.is_match(line) {
+                    each_index(patterns, |pat| line.contains(pat), f);
+                }
+            }
+            #[cfg(not(feature = "aho-corasick"))]
+            Patterns::Contains(patterns) => each_index(patterns, |pat| line.contains(pat), f),
+            Patterns::StartsWith(patterns) => each_index(patterns, |pat| line.starts_with(pat), f),
+            Patterns::EndsWith(patterns) => each_index(patterns, |pat| line.ends_with(pat), f),
+            Patterns::Exact(patterns) => each_index(patterns, |pat| line == pat, f),
+            Patterns::Regex(regexes) => {
+                for (index, re) in regexes.iter().enumerate() {
+                    if re.is_match(line) {
+                        f(index);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The line with every match replaced by `replacement`, borrowed if nothing matched
+    fn redact<'a>(&self, line: &'a str, replacement: &str) -> Cow<'a, str> {
+        match self {
+            #[cfg(feature = "aho-corasick")]
+            Patterns::Contains(automaton, _) => {
+                let mut redacted = String::with_capacity(line.len());
+                automaton.replace_all_with(line, &mut redacted, |_, _, dst| {
+                    dst.push_str(replacement);
+                    true
+                });
+                Cow::Owned(redacted)
+            }
+            #[cfg(not(feature = "aho-corasick"))]
+            Patterns::Contains(patterns) => {
+                patterns.iter().fold(Cow::Borrowed(line), |line, pat| {
+                    if line.contains(pat.as_str()) {
+                        Cow::Owned(line.replace(pat.as_str(), replacement))
+                    } else {
+                        line
+                    }
+                })
+            }
+            Patterns::StartsWith(patterns) => {
+                match patterns
+                    .iter()
+                    .find_map(|pat| line.strip_prefix(pat.as_str()))
+                {
+                    Some(rest) => Cow::Owned(format!("{replacement}{rest}")),
+                    None => Cow::Borrowed(line),
+                }
+            }
+            Patterns::EndsWith(patterns) => {
+                match patterns
+                    .iter()
+                    .find_map(|pat| line.strip_suffix(pat.as_str()))
+                {
+                    Some(rest) => Cow::Owned(format!("{rest}{replacement}")),
+                    None => Cow::Borrowed(line),
+                }
+            }
+            Patterns::Exact(patterns) if patterns.iter().any(|pat| line == pat) => {
+                Cow::Owned(replacement.to_string())
+            }
+            Patterns::Exact(_) => Cow::Borrowed(line),
+            Patterns::Regex(regexes) => {
+                regexes.iter().fold(Cow::Borrowed(line), |line, re| {
+                    match re.replace_all(&line, replacement) {
+                        Cow::Borrowed(_) => line,
+                        Cow::Owned(redacted) => Cow::Owned(redacted),
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// Call `f` with the index of each pattern for which `is_match` holds
+fn each_index(patterns: &[String], is_match: impl Fn(&str) -> bool, mut f: impl FnMut(usize)) {
+    for (index, pat) in patterns.iter().enumerate() {
+        if is_match(pat) {
+            f(index);
+        }
+    }
+}
+
+/// Process all files, reporting progress and returning a summary of the run.
+/// Failed files are logged and counted unless `fail_fast` (or [`SieveOptions::strict`])
+/// is set, in which case the first failure aborts the run.
+pub fn process_files(
+    gz_files: &[(PathBuf, u64)],
+    options: &SieveOptions,
+    total_size: u64,
+    run_options: &RunOptions,
+) -> Result<RunSummary, SieveError> {
+    let start = Instant::now();
+    // Rayon would quietly treat 0 as "pick for me", which isn't what was asked for
+    if run_options.threads == Some(0) {
+        return Err(SieveError::InvalidOptions(
+            "the thread count must be at least 1".to_string(),
+        ));
+    }
+
+    // Compile patterns once up front so every worker shares the same matcher
+    let matcher = Arc::new(Matcher::new(&options.patterns, options.match_kind)?);
+
+    if let Some(progress) = &run_options.progress {
+        progress.on_start(total_size);
+    }
+
+    // Atomic counters for lines and bytes across all files, and files handled
+    let total_lines_read = Arc::new(AtomicU64::new(0));
+    let total_lines_removed = Arc::new(AtomicU64::new(0));
+    let total_lines_collapsed = Arc::new(AtomicU64::new(0));
+    let total_bytes_before = Arc::new(AtomicU64::new(0));
+    let total_bytes_after = Arc::new(AtomicU64::new(0));
+    let files_processed = Arc::new(AtomicU64::new(0));
+    let failures = Mutex::new(Vec::new());
+    let file_results = Mutex::new(Vec::new());
+    let file_seconds = Mutex::new(Vec::new());
+    // One warning is enough when the filesystem can't hold the marker at all
+    let mark_warned = AtomicBool::new(false);
+    // Bucketed as files finish, so the run's size doesn't matter
+    let histogram: [AtomicU64; HISTOGRAM_BUCKETS] = Default::default();
+
+    let process_file = |(file_path, file_size): &(PathBuf, u64)| {
+        // Files already in flight finish normally; nothing new is started
+        if run_options.cancelled.load(Ordering::Relaxed) {
+            return Err(SieveError::Cancelled);
+        }
+        // Cancelled through the options, the run ends quietly with what's been done so far
+        if options.is_cancelled() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let outcome = filter_lines(file_path, &matcher, options);
+        let elapsed = started.elapsed();
+        if let Some(progress) = &run_options.progress {
+            progress.on_progress(*file_size);
+        }
+        match outcome {
+            Err(SieveError::Cancelled) => Ok(()),
+            Ok(file_result) => {
+                total_lines_read.fetch_add(file_result.read, Ordering::Relaxed);
+                total_lines_removed.fetch_add(file_result.removed, Ordering::Relaxed);
+                total_lines_collapsed.fetch_add(file_result.collapsed, Ordering::Relaxed);
+                total_bytes_before.fetch_add(file_result.bytes_before, Ordering::Relaxed);
+                total_bytes_after.fetch_add(file_result.bytes_after, Ordering::Relaxed);
+                if run_options.histogram {
+                    histogram[histogram_bucket(file_result.removed)]
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                files_processed.fetch_add(1, Ordering::Relaxed);
+                if run_options.time_files {
+                    file_seconds.lock().unwrap().push(elapsed.as_secs_f64());
+                }
+                if run_options.file_results {
+                    file_results
+                        .lock()
+                        .unwrap()
+                        .push((file_path.clone(), file_result));
+                }
+                if let Some(checkpoint) = &run_options.checkpoint
+                    && let Err(e) = record_checkpoint(checkpoint, file_path)
+                {
+                    let message = format!("Failed to checkpoint {}: {e}", file_path.display());
+                    if options.strict {
+                        return Err(SieveError::Strict(message));
+                    }
+                    warn!("{message}");
+                }
+                // A file that was deleted or converted under a new name has nothing to mark
+                if run_options.mark_processed
+                    && !options.dry_run
+                    && !options.count_only
+                    && !is_url(file_path)
+                    && let Err(e) = mark_processed(file_path)
+                    && e.kind() != ErrorKind::NotFound
+                {
+                    let message = format!(
+                        "Can't mark {} as processed, so it will be processed again next time: {e}",
+                        file_path.display()
+                    );
+                    if options.strict {
+                        return Err(SieveError::Strict(message));
+                    }
+                    if !mark_warned.swap(true, Ordering::Relaxed) {
+                        warn!("{message}");
+                    }
+                }
+                Ok(())
+            }
+            Err(e) if run_options.fail_fast || options.strict => Err(SieveError::FileFailed {
+                path: file_path.display().to_string(),
+                source: Box::new(e),
+            }),
+            Err(e) => {
+                warn!("Error processing {}: {}", file_path.display(), e);
+                failures.lock().unwrap().push((file_path.clone(), e));
+                Ok(())
+            }
+        }
+    };
+
+    let result = if run_options.sequential {
+        // By path on the calling thread, so the logs come out the same every time
+        let mut ordered: Vec<_> = gz_files.iter().collect();
+        ordered.sort_by(|a, b| a.0.cmp(&b.0));
+        ordered.into_iter().try_for_each(process_file)
+    } else {
+        // Use available CPU cores if threads not specified
+        let thread_count = run_options.threads.unwrap_or_else(num_cpus::get);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .build()?;
+
+        let ordered = largest_first(gz_files);
+        pool.install(|| {
+            // One file per task, so idle workers steal the next file rather than half a chunk
+            ordered
+                .par_iter()
+                .with_max_len(1)
+                .try_for_each(|file| process_file(file))
+        })
+    };
+
+    if let Some(progress) = &run_options.progress {
+        progress.on_finish(result.is_ok());
+    }
+
+    if let Some(removed_output) = &options.removed_output {
+        removed_output.lock().unwrap().flush()?;
+    }
+    if let Some(diff_output) = &options.diff_output {
+        diff_output.0.lock().unwrap().flush()?;
+    }
+    result?;
+
+    // Report failures in a stable order regardless of which worker hit them
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut file_results = file_results.into_inner().unwrap();
+    file_results.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut file_seconds = file_seconds.into_inner().unwrap();
+    file_seconds.sort_by(f64::total_cmp);
+
+    Ok(RunSummary {
+        total_lines_read: total_lines_read.load(Ordering::Relaxed),
+        total_lines_removed: total_lines_removed.load(Ordering::Relaxed),
+        total_lines_collapsed: total_lines_collapsed.load(Ordering::Relaxed),
+        total_bytes_before: total_bytes_before.load(Ordering::Relaxed),
+        total_bytes_after: total_bytes_after.load(Ordering::Relaxed),
+        files_processed: files_processed.load(Ordering::Relaxed),
+        files_skipped: 0,
+        files_failed: failures.len() as u64,
+        elapsed_seconds: start.elapsed().as_secs_f64(),
+        pattern_hits: match &options.pattern_hits {
+            Some(hits) => options
+                .patterns
+                .iter()
+                .cloned()
+                .zip(hits.counts())
+                .collect(),
+            None => Vec::new(),
+        },
+        removal_histogram: {
+            let mut counts: Vec<u64> = histogram
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect();
+            while counts.last() == Some(&0) {
+                counts.pop();
+            }
+            counts
+        },
+        failures,
+        file_results,
+        file_seconds,
+    })
+}
+
+/// Line counts for a single filtered file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileResult {
+    /// Lines read from the original
+    pub read: u64,
+    /// Lines left out of the rewritten file (those not matching in keep mode),
+    /// or the lines redacted in redact mode
+    pub removed: u64,
+    /// Kept lines left out as repeats of the one before, with
+    /// [`SieveOptions::dedup_consecutive`]
+    pub collapsed: u64,
+    /// On-disk size of the original
+    pub bytes_before: u64,
+    /// On-disk size of the result: unchanged if the file was left alone, 0 if it was deleted
+    pub bytes_after: u64,
+}
+
+/// Gather all files with a supported extension under each root and compute their sizes.
+/// With `detect_magic`, other files are opened and kept if they start with a gzip header.
+/// A file reachable from several roots (or through symlinks) is only listed once.
+/// A root that is itself a file is listed as-is, since naming it is selection enough.
+/// Paths that can't be read are skipped with a warning, or fail the search if `strict`.
+pub fn gather_gz_files<P: AsRef<Path>>(
+    roots: &[P],
+    options: &GatherOptions,
+) -> Result<(Vec<(PathBuf, u64)>, u64), SieveError> {
+    gather_files(roots, options).map(|gathered| (gathered.files, gathered.total_size))
+}
+
+/// What [`gather_files`] found
+#[derive(Debug, Default)]
+pub struct Gathered {
+    /// Each file to process and its size
+    pub files: Vec<(PathBuf, u64)>,
+    pub total_size: u64,
+    /// Files of a supported format left out by the selection settings: the globs, size
+    /// and age limits, and `skip_processed`
+    pub skipped: u64,
+}
+
+/// [`gather_gz_files`], also counting the files the selection settings left out
+pub fn gather_files<P: AsRef<Path>>(
+    roots: &[P],
+    options: &GatherOptions,
+) -> Result<Gathered, SieveError> {
+    let mut gz_files = Vec::new();
+    let mut total_size = 0_u64;
+    let mut skipped = 0_u64;
+    let mut seen = HashSet::new();
+
+    for root in roots {
+        let root = root.as_ref();
+        if is_url(root) {
+            if seen.insert(root.to_path_buf()) {
+                gz_files.push((root.to_path_buf(), 0));
+            }
+            continue;
+        }
+        if let Ok(metadata) = std::fs::metadata(root)
+            && metadata.is_file()
+        {
+            let canonical = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+            if options.skip_processed && is_marked_processed(root) {
+                debug!("Skipping {}: already processed", root.display());
+                skipped += 1;
+                continue;
+            }
+            if seen.insert(canonical) {
+                total_size += metadata.len();
+                gz_files.push((root.to_path_buf(), metadata.len()));
+            }
+            continue;
+        }
+
+        // WalkDir detects symlink loops itself and reports them as errors
+        let mut walker = WalkDir::new(root).follow_links(options.follow_symlinks);
+        if let Some(depth) = options.max_depth {
+            // WalkDir counts the root itself as depth 0, so its direct files are at depth 1
+            walker = walker.max_depth(depth + 1);
+        }
+        let mut ignore_rules = IgnoreRules::default();
+        let walker = walker.into_iter().filter_entry(|entry| {
+            entry.depth() == 0
+                || !ignore_rules.is_ignored(root, entry.path(), entry.file_type().is_dir())
+        });
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if options.strict => {
+                    return Err(SieveError::Strict(format!("Unreadable path: {e}")));
+                }
+                Err(e) => {
+                    warn!("Skipping unreadable path during traversal: {e}");
+                    continue;
+                }
+            };
+            if entry.file_type().is_file()
+                && (Codec::from_path(entry.path(), &options.plain_extensions).is_some()
+                    || (options.detect_magic && Codec::has_gzip_magic(entry.path())))
+            {
+                if !options.is_selected(entry.path()) {
+                    skipped += 1;
+                    continue;
+                }
+                let canonical = entry
+                    .path()
+                    .canonicalize()
+                    .unwrap_or_else(|_| entry.path().to_path_buf());
+                if !seen.insert(canonical) {
+                    continue;
+                }
+                let metadata = entry.metadata().ok();
+                let size = metadata.as_ref().map_or(0, |m| m.len());
+                if !options.size_in_range(size) {
+                    skipped += 1;
+                    continue;
+                }
+                if let Some(modified) = metadata.and_then(|m| m.modified().ok())
+                    && !options.modified_in_range(modified)
+                {
+                    skipped += 1;
+                    continue;
+                }
+                if options.skip_processed && is_marked_processed(entry.path()) {
+                    debug!("Skipping {}: already processed", entry.path().display());
+                    skipped += 1;
+                    continue;
+                }
+                total_size += size;
+                gz_files.push((entry.path().to_path_buf(), size));
+            }
+        }
+        if options.strict
+            && let Some(problem) = ignore_rules.problems.into_iter().next()
+        {
+            return Err(SieveError::Strict(problem));
+        }
+    }
+
+    Ok(Gathered {
+        files: gz_files,
+        total_size,
+        skipped,
+    })
+}
+
+/// Extended attribute set on a file once it has been processed, holding the time it was
+pub const PROCESSED_XATTR: &str = "user.sieve.processed";
+
+/// Whether `path` carries the [`PROCESSED_XATTR`] marker. Nothing does on a filesystem
+/// without extended attributes.
+fn is_marked_processed(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(xattr::get(path, PROCESSED_XATTR), Ok(Some(_)))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Stamp `path` with [`PROCESSED_XATTR`], set to the current time
+fn mark_processed(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let now = chrono::Local::now().to_rfc3339();
+        xattr::set(path, PROCESSED_XATTR, now.as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+/// Canonical paths already listed in a checkpoint file; a missing file means a fresh start
+pub fn read_checkpoint(path: &Path) -> Result<HashSet<PathBuf>, SieveError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(PathBuf::from).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(SieveError::FileOpen {
+            path: path.display().to_string(),
+            source: e,
+        }),
+    }
+}
+
+/// Drop files a previous run already finished, recomputing the total size of what's left
+pub fn skip_checkpointed(
+    files: Vec<(PathBuf, u64)>,
+    done: &HashSet<PathBuf>,
+) -> (Vec<(PathBuf, u64)>, u64) {
+    let remaining: Vec<_> = files
+        .into_iter()
+        .filter(|(path, _)| {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            !done.contains(&canonical)
+        })
+        .collect();
+    let total_size = remaining.iter().map(|(_, size)| size).sum();
+    (remaining, total_size)
+}
+
+/// Keep only the first `limit` files in path order, recomputing their total size. Sorting
+/// first makes the cut the same on every run, whatever order the search found them in.
+pub fn limit_files(mut files: Vec<(PathBuf, u64)>, limit: usize) -> (Vec<(PathBuf, u64)>, u64) {
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    files.truncate(limit);
+    let total_size = files.iter().map(|(_, size)| size).sum();
+    (files, total_size)
+}
+
+/// Take an exclusive advisory lock on `path`, creating the file if need be, so runs
+/// sharing a lock file never overlap. If another run holds it, this waits for it to
+/// be released with `wait` and fails with [`SieveError::Locked`] otherwise. The lock
+/// is held for as long as the returned file stays open.
+pub fn lock_run(path: &Path, wait: bool) -> Result<File, SieveError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| SieveError::FileOpen {
+            path: path.display().to_string(),
+            source: e,
+        })?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) if wait => {
+            info!("Waiting for another run to release {}", path.display());
+            file.lock()?;
+            Ok(file)
+        }
+        Err(TryLockError::WouldBlock) => Err(SieveError::Locked(path.display().to_string())),
+        Err(TryLockError::Error(e)) => Err(SieveError::Io(e)),
+    }
+}
+
+/// Append a finished file's canonical path to the checkpoint
+fn record_checkpoint(checkpoint: &Mutex<File>, file_path: &Path) -> std::io::Result<()> {
+    let canonical = match file_path.canonicalize() {
+        Ok(canonical) => canonical,
+        // Deleted by --delete-emptied, so there's nothing to find next time anyway
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // One write per entry so concurrent workers can't interleave within a line
+    let entry = format!("{}\n", canonical.display());
+    checkpoint.lock().unwrap().write_all(entry.as_bytes())
+}
+
+/// Read newline-separated paths to process, as an alternative to walking a directory tree.
+/// Entries that don't exist or aren't regular files are skipped with a warning, or fail
+/// the list if `strict`.
+pub fn read_file_list(
+    list: impl BufRead,
+    strict: bool,
+) -> Result<(Vec<(PathBuf, u64)>, u64), SieveError> {
+    let mut files = Vec::new();
+    let mut total_size = 0_u64;
+    let skip = |message: String| {
+        if strict {
+            return Err(SieveError::Strict(message));
+        }
+        warn!("{message}");
+        Ok(())
+    };
+
+    for line in list.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                skip(format!("Stopped reading the file list: {e}"))?;
+                break;
+            }
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        // Fetched when processed, so their size isn't known up front
+        if is_url(&path) {
+            files.push((path, 0));
+            continue;
+        }
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => {
+                total_size += metadata.len();
+                files.push((path, metadata.len()));
+            }
+            Ok(_) => skip(format!(
+                "Skipping listed path that isn't a file: {}",
+                path.display()
+            ))?,
+            Err(e) => skip(format!("Skipping listed file {}: {e}", path.display()))?,
+        }
+    }
+
+    Ok((files, total_size))
+}
+
+/// Orders files biggest first. Starting the largest files early keeps one huge
+/// file from being picked up last and running alone while every other worker idles.
+fn largest_first(gz_files: &[(PathBuf, u64)]) -> Vec<&(PathBuf, u64)> {
+    let mut ordered: Vec<_> = gz_files.iter().collect();
+    ordered.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    ordered
+}
+
+/// Filters lines in a single compressed file based on mode.
+/// The codec is chosen from the file extension, defaulting to gzip.
+/// In Remove mode, removes lines matching any pattern.
+/// In Keep mode, keeps only lines matching any pattern.
+/// In a dry run the lines are counted but the file is left untouched.
+pub fn filter_lines(
+    file_path: &Path,
+    matcher: &Matcher,
+    options: &SieveOptions,
+) -> Result<FileResult, SieveError> {
+    if is_url(file_path) {
+        return filter_url(file_path, matcher, options);
+    }
+    let codec = Codec::from_path(file_path, &options.plain_extensions).unwrap_or(Codec::Gzip);
+
+    let in_file = File::open(file_path).map_err(|e| SieveError::FileOpen {
+        path: file_path.display().to_string(),
+        source: e,
+    })?;
+
+    let original_metadata = in_file.metadata()?;
+    let input: Box<dyn Read> = if options.mmap && original_metadata.len() >= MIN_MMAP_SIZE {
+        // SAFETY: the map is only read, and sieve never writes to a file while reading it.
+        // Another process truncating the file mid-run could still fault the read, which is
+        // the documented trade-off of --mmap.
+        match unsafe { Mmap::map(&in_file) } {
+            Ok(map) => Box::new(Cursor::new(map)),
+            Err(e) => {
+                debug!("Reading {} normally, mmap failed: {e}", file_path.display());
+                Box::new(in_file)
+            }
+        }
+    } else {
+        Box::new(in_file)
+    };
+    let buffer_size = options.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    let mut reader = BufReader::with_capacity(buffer_size, codec.reader(input)?);
+
+    // Filtered output goes to the mirrored output location, or replaces the original,
+    // under a new name if it's changing format
+    let out_codec = options.output_codec.unwrap_or(codec);
+    let target = match &options.output_dir {
+        Some(output_dir) => output_dir.target_for(file_path)?,
+        None => file_path.to_path_buf(),
+    };
+    let target = out_codec.rename(&target, codec);
+    let renamed = options.output_dir.is_none() && target != file_path;
+    if renamed && target.exists() {
+        return Err(SieveError::Processing(format!(
+            "Refusing to overwrite {} with the converted {}",
+            target.display(),
+            file_path.display()
+        )));
+    }
+
+    let (temp_file, mut writer) = output_for(&target, out_codec, options)?;
+
+    let source = file_path.display().to_string();
+    let tar_archive = options.tar && is_tar_archive(file_path);
+    // Line numbers within an archive's members wouldn't make a meaningful diff
+    let mut diff = options
+        .diff_output
+        .as_ref()
+        .filter(|_| !tar_archive)
+        .map(|_| Diff::default());
+    let mut collapsed_count = 0;
+    let sieved = match options.parallel_above {
+        _ if tar_archive => sieve_tar(
+            &mut reader,
+            &mut writer,
+            &source,
+            matcher,
+            options,
+            &mut collapsed_count,
+        ),
+        // The diff needs the removed lines in file order, and a run of repeats can
+        // straddle two chunks, so either one means going sequentially
+        Some(threshold)
+            if original_metadata.len() >= threshold
+                && diff.is_none()
+                && !options.dedup_consecutive =>
+        {
+            let workers = rayon::current_num_threads();
+            sieve_stream_parallel(&mut reader, &mut writer, &source, matcher, options, workers)
+        }
+        _ => sieve_stream_with(
+            &mut reader,
+            &mut writer,
+            &source,
+            matcher,
+            options,
+            Stream {
+                at_start: true,
+                diff: diff.as_mut(),
+                collapsed: Some(&mut collapsed_count),
+            },
+        ),
+    };
+    let (read_count, dropped_count) = match sieved {
+        Ok(counts) => counts,
+        Err(e) => {
+            if options.keep_temp_on_error {
+                keep_partial(temp_file, writer, &target, &e);
+            }
+            return Err(e);
+        }
+    };
+    writer.flush().map_err(SieveError::Io)?; // Ensure compression is finalized
+    drop(writer); // Close the encoder before replacing file
+
+    if let (Some(diff_output), Some(diff)) = (&options.diff_output, diff)
+        && dropped_count + collapsed_count > 0
+    {
+        diff.write_to(&mut *diff_output.0.lock().unwrap(), &source)?;
+    }
+
+    let action = match options.mode {
+        Mode::Remove => "removed",
+        Mode::Keep => "kept",
+        Mode::Redact => "redacted",
+    };
+    let matched_count = match options.mode {
+        Mode::Remove | Mode::Redact => dropped_count,
+        Mode::Keep => read_count - dropped_count,
+    };
+    debug!(
+        "Processed {}: {action} {} lines of {} total lines.",
+        file_path.display(),
+        matched_count,
+        read_count,
+    );
+    if collapsed_count > 0 {
+        debug!(
+            "Collapsed {collapsed_count} repeated lines in {}.",
+            file_path.display()
+        );
+    }
+
+    let mut result = FileResult {
+        read: read_count,
+        removed: dropped_count,
+        collapsed: collapsed_count,
+        bytes_before: original_metadata.len(),
+        bytes_after: original_metadata.len(),
+    };
+    if options.count_only {
+        return Ok(result);
+    }
+
+    // Checked after the full pass so the original is only ever replaced in one go;
+    // the temp file is discarded when it goes out of scope
+    if let Some(max_ratio) = options.max_removal_ratio
+        && read_count > 0
+        && dropped_count as f64 / read_count as f64 > max_ratio
+    {
+        return Err(SieveError::RemovalRatioExceeded {
+            path: file_path.display().to_string(),
+            dropped: dropped_count,
+            read: read_count,
+            max_ratio,
+        });
+    }
+
+    // Nothing was dropped, so leave the original untouched rather than recompressing it
+    if dropped_count == 0
+        && collapsed_count == 0
+        && options.output_dir.is_none()
+        && out_codec == codec
+    {
+        return Ok(result);
+    }
+
+    // Redacted lines stay in the file, so redacting can never empty it, and neither can
+    // removing every counted line when skipped lines are still there
+    let emptied = options.mode != Mode::Redact
+        && !tar_archive
+        && options.skip_lines == 0
+        && read_count > 0
+        && dropped_count == read_count;
+    if emptied && !options.delete_emptied {
+        let message = if options.no_empty {
+            format!(
+                "Leaving {} untouched: every line would be dropped.",
+                file_path.display()
+            )
+        } else {
+            format!(
+                "Every line was dropped from {}; it will be left empty.",
+                file_path.display()
+            )
+        };
+        // Failing before anything is written leaves the file as it was
+        if options.strict {
+            return Err(SieveError::Strict(message));
+        }
+        warn!("{message}");
+        if options.no_empty {
+            return Ok(result);
+        }
+    }
+
+    let delete = emptied && options.delete_emptied;
+    if delete && options.dry_run {
+        debug!(
+            "Would delete {}: every line was dropped.",
+            file_path.display()
+        );
+    }
+
+    let read_only = original_metadata.permissions().readonly();
+    if let Some(temp_file) = temp_file {
+        // Before anything is backed up or replaced, so a bad output never costs the original
+        if options.verify && !delete {
+            verify_output(temp_file.path(), out_codec, &target)?;
+        }
+        if options.output_dir.is_none() {
+            if read_only && !options.force {
+                return Err(SieveError::Processing(format!(
+                    "Refusing to replace read-only file {}",
+                    file_path.display()
+                )));
+            }
+            if let Some(suffix) = &options.backup_suffix {
+                backup_file(file_path, suffix, options.keep_existing_backup)?;
+            }
+        }
+
+        // The empty temp file is discarded on drop; with an output directory the
+        // original was never going to be touched, so there is simply nothing to write
+        if delete {
+            if options.output_dir.is_none() {
+                std::fs::remove_file(file_path).map_err(SieveError::Io)?;
+            }
+            debug!("Deleted {}: every line was dropped.", file_path.display());
+            result.bytes_after = 0;
+            return Ok(result);
+        }
+
+        // Set before the rename, so the new file never shows up with the temp file's 0600
+        std::fs::set_permissions(temp_file.path(), original_metadata.permissions())?;
+        replace_file(temp_file, &target, options.force, options.fsync)?;
+        // Converted in place: the new file stands in for the original from here on
+        if renamed {
+            std::fs::remove_file(file_path).map_err(SieveError::Io)?;
+        }
+
+        if options.preserve_metadata {
+            restore_times(&target, &original_metadata)?;
+        }
+        result.bytes_after = std::fs::metadata(&target)?.len();
+    }
+
+    Ok(result)
+}
+
+/// A temporary file near `target` and a writer compressing into it, or no file and
+/// a writer discarding everything on a dry run. A benchmark compresses the output
+/// but doesn't keep it either.
+fn output_for(
+    target: &Path,
+    codec: Codec,
+    options: &SieveOptions,
+) -> Result<(Option<NamedTempFile>, Box<dyn Write>), SieveError> {
+    if options.dry_run || options.count_only {
+        return Ok((None, Box::new(std::io::sink())));
+    }
+    let (temp_file, out): (_, Box<dyn Write>) = if options.benchmark {
+        (None, Box::new(std::io::sink()))
+    } else {
+        let temp_file = create_temp_file_near(target)?;
+        let out_file = File::create(temp_file.path()).map_err(SieveError::Io)?;
+        (Some(temp_file), Box::new(out_file))
+    };
+    let encoder: Box<dyn Write> = if codec == Codec::Gzip && options.parallel_gzip {
+        let level = options
+            .compression_level
+            .map_or_else(Compression::default, Compression::new);
+        Box::new(ParallelGzEncoder::new(BufWriter::new(out), level))
+    } else {
+        codec.writer(out, options.compression_level)?
+    };
+    let writer =
+        BufWriter::with_capacity(options.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE), encoder);
+    Ok((temp_file, Box::new(writer)))
+}
+
+/// Whether `path` is really an `http://` or `https://` URL
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+/// Where a URL's file goes below an output directory: its host, then its path,
+/// e.g. `example.com/logs/app.log.gz`. `None` if it doesn't name a file or
+/// tries to climb out with `..`.
+fn url_relative_path(url: &str) -> Option<PathBuf> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    let rest = rest.split(['?', '#']).next()?;
+    let mut relative = PathBuf::new();
+    for segment in rest.split('/').filter(|segment| !segment.is_empty()) {
+        if segment == "." || segment == ".." {
+            return None;
+        }
+        // A port after the host isn't allowed in a Windows file name
+        relative.push(segment.replace(':', "_"));
+    }
+    (relative.components().count() >= 2 && !rest.ends_with('/')).then_some(relative)
+}
+
+/// [`filter_lines`] for a file fetched over HTTP(S). A URL can't be replaced, so the
+/// result is always written below the output directory, which is required.
+#[cfg(feature = "http")]
+fn filter_url(
+    url: &Path,
+    matcher: &Matcher,
+    options: &SieveOptions,
+) -> Result<FileResult, SieveError> {
+    let source = url.display().to_string();
+    let Some(output_dir) = &options.output_dir else {
+        return Err(SieveError::InvalidOptions(format!(
+            "{source} can't be filtered in place; give an output directory"
+        )));
+    };
+    // The local path has no query string to confuse the extension
+    let target = output_dir.target_for(url)?;
+    let codec = Codec::from_path(&target, &options.plain_extensions).unwrap_or(Codec::Gzip);
+    let out_codec = options.output_codec.unwrap_or(codec);
+    let target = out_codec.rename(&target, codec);
+
+    let fetch_error =
+        |e: reqwest::Error| SieveError::Processing(format!("Failed to fetch {source}: {e}"));
+    // No overall timeout: a large download can legitimately take a long time
+    let response = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .and_then(|client| client.get(source.as_str()).send())
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .map_err(fetch_error)?;
+    let bytes_before = response.content_length().unwrap_or(0);
+    let buffer_size = options.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    let mut reader = BufReader::with_capacity(buffer_size, codec.reader(response)?);
+
+    let (temp_file, mut writer) = output_for(&target, out_codec, options)?;
+    let mut collapsed = 0;
+    let stream = Stream {
+        at_start: true,
+        diff: None,
+        collapsed: Some(&mut collapsed),
+    };
+    let (read, removed) =
+        match sieve_stream_with(&mut reader, &mut writer, &source, matcher, options, stream) {
+            Ok(counts) => counts,
+            Err(e) => {
+                if options.keep_temp_on_error {
+                    keep_partial(temp_file, writer, &target, &e);
+                }
+                return Err(e);
+            }
+        };
+    writer.flush().map_err(SieveError::Io)?;
+    drop(writer);
+    debug!("Processed {source}: dropped {removed} lines of {read} total lines.");
+
+    let mut result = FileResult {
+        read,
+        removed,
+        collapsed,
+        bytes_before,
+        bytes_after: bytes_before,
+    };
+    if let Some(temp_file) = temp_file {
+        replace_file(temp_file, &target, false, options.fsync)?;
+        result.bytes_after = std::fs::metadata(&target)?.len();
+    }
+    Ok(result)
+}
+
+#[cfg(not(feature = "http"))]
+fn filter_url(
+    url: &Path,
+    _matcher: &Matcher,
+    _options: &SieveOptions,
+) -> Result<FileResult, SieveError> {
+    Err(SieveError::Processing(format!(
+        "Can't fetch {}: sieve was built without the http feature",
+        url.display()
+    )))
+}
+
+/// Filters records from `reader` into `writer`, returning the lines read and removed.
+/// This is the core of [`filter_lines`] without any files or compression involved, so
+/// it works just as well on in-memory buffers or sockets. `source` names the input in
+/// errors and in the lines sent to `removed_output`. The writer isn't flushed, and
+/// lines collapsed by [`SieveOptions::dedup_consecutive`] aren't counted as removed.
+pub fn sieve_stream<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    source: &str,
+    matcher: &Matcher,
+    options: &SieveOptions,
+) -> Result<(u64, u64), SieveError> {
+    let stream = Stream {
+        at_start: true,
+        ..Stream::default()
+    };
+    sieve_stream_with(reader, writer, source, matcher, options, stream)
+}
+
+/// How a stream handed to [`sieve_records`] relates to the file it came from
+#[derive(Default)]
+struct Stream<'a> {
+    /// Whether it starts at the top of the file, where the header lines are
+    at_start: bool,
+    /// Collects every dropped record, when a diff is wanted
+    diff: Option<&'a mut Diff>,
+    /// Adds up the records collapsed into the one before them
+    collapsed: Option<&'a mut u64>,
+}
+
+/// [`sieve_stream`] for a stream described by `stream`
+fn sieve_stream_with<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    source: &str,
+    matcher: &Matcher,
+    options: &SieveOptions,
+    stream: Stream,
+) -> Result<(u64, u64), SieveError> {
+    // Taken rather than borrowed so an `on_removed` callback can sieve another stream
+    let mut buf = RECORD_BUFFER.take();
+    let result = sieve_records(reader, writer, source, matcher, options, stream, &mut buf);
+    if buf.capacity() <= MAX_REUSED_RECORD_CAPACITY {
+        RECORD_BUFFER.set(buf);
+    }
+    result
+}
+
+/// Whether the file is a compressed tar archive, such as `logs.tar.gz`
+fn is_tar_archive(path: &Path) -> bool {
+    path.file_stem()
+        .map(Path::new)
+        .and_then(Path::extension)
+        .is_some_and(|ext| ext == "tar")
+}
+
+/// Bytes at the start of a tar member checked for NUL bytes, which mark it as binary
+const TEXT_SNIFF_LEN: usize = 8192;
+
+/// Sieves each text member of the tar archive in `reader` into a new archive on
+/// `writer`, returning the lines read and removed across all of them and adding the
+/// ones collapsed to `collapsed` (runs of repeats don't carry over between members).
+/// Directories, links and members that look binary are copied as they are, and every
+/// entry keeps its mode, owner and timestamps. A member is held in memory while it's
+/// filtered, as its new size has to go in the header ahead of the data.
+fn sieve_tar<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    source: &str,
+    matcher: &Matcher,
+    options: &SieveOptions,
+    collapsed: &mut u64,
+) -> Result<(u64, u64), SieveError> {
+    let mut archive = tar::Archive::new(reader);
+    let mut builder = tar::Builder::new(writer);
+    let mut counts = (0_u64, 0_u64);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // Read through the entry rather than from its header, which can only hold short names
+        let path = entry.path()?.into_owned();
+        let mut header = entry.header().clone();
+        if header.entry_type().is_file() {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            let sniffed = &data[..data.len().min(TEXT_SNIFF_LEN)];
+            if !sniffed.contains(&0) && (options.binary_safe || std::str::from_utf8(&data).is_ok())
+            {
+                let member = format!("{source}:{}", path.display());
+                let mut filtered = Vec::with_capacity(data.len());
+                let stream = Stream {
+                    at_start: true,
+                    diff: None,
+                    collapsed: Some(&mut *collapsed),
+                };
+                let (read, removed) =
+                    sieve_stream_with(&data[..], &mut filtered, &member, matcher, options, stream)?;
+                counts.0 += read;
+                counts.1 += removed;
+                data = filtered;
+            }
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, &path, &data[..])?;
+        } else if header.entry_type().is_symlink() || header.entry_type().is_hard_link() {
+            let target = entry.link_name()?.map(Cow::into_owned).unwrap_or_default();
+            builder.append_link(&mut header, &path, target)?;
+        } else {
+            builder.append_data(&mut header, &path, entry)?;
+        }
+    }
+    // Writes the blocks that mark the end of the archive
+    builder.into_inner()?;
+    Ok(counts)
+}
+
+/// Records handed to a worker at a time by [`sieve_stream_parallel`]
+const PARALLEL_CHUNK_SIZE: usize = 256 * 1024;
+
+/// [`sieve_stream`] spread over `workers` threads. The reader and writer stay on the
+/// calling thread, which cuts the stream into chunks of whole records, hands them
+/// to the workers and writes their output back in the original order. Lines sent
+/// to `removed_output` may come out in a different order than a sequential run, and
+/// with [`SieveOptions::dedup_consecutive`] a run of repeats split between two chunks
+/// leaves one line in each.
+pub fn sieve_stream_parallel<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    source: &str,
+    matcher: &Matcher,
+    options: &SieveOptions,
+    workers: usize,
+) -> Result<(u64, u64), SieveError> {
+    type ChunkResult = Result<(u64, Vec<u8>, (u64, u64)), SieveError>;
+
+    let read_error = |e| SieveError::LineRead {
+        path: source.to_string(),
+        source: e,
+    };
+    // Skipped and header lines are written through here, before any chunking
+    let mut header_read = 0_u64;
+    let mut header = Vec::new();
+    for index in 0..options.skip_lines + options.keep_header {
+        header.clear();
+        if reader
+            .read_until(options.delimiter.0, &mut header)
+            .map_err(read_error)?
+            == 0
+        {
+            break;
+        }
+        writer.write_all(&header).map_err(SieveError::Io)?;
+        if index >= options.skip_lines {
+            header_read += 1;
+        }
+    }
+
+    // Bounded, so only a few chunks per worker are ever held in memory
+    let (chunk_sender, chunk_receiver) =
+        std::sync::mpsc::sync_channel::<(u64, Vec<u8>)>(workers * 2);
+    let chunk_receiver = Mutex::new(chunk_receiver);
+    std::thread::scope(|scope| {
+        let (result_sender, result_receiver) = std::sync::mpsc::channel::<ChunkResult>();
+        for _ in 0..workers.max(1) {
+            let chunk_receiver = &chunk_receiver;
+            let result_sender = result_sender.clone();
+            scope.spawn(move || {
+                loop {
+                    let received = chunk_receiver.lock().unwrap().recv();
+                    let Ok((index, chunk)) = received else {
+                        break;
+                    };
+                    let mut output = Vec::with_capacity(chunk.len());
+                    // The header was already written, so no chunk is at the start of the file
+                    let stream = Stream::default();
+                    let result = sieve_stream_with(
+                        &chunk[..],
+                        &mut output,
+                        source,
+                        matcher,
+                        options,
+                        stream,
+                    )
+                    .map(|counts| (index, output, counts));
+                    if result_sender.send(result).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        let mut pending = std::collections::BTreeMap::new();
+        let mut next_to_write = 0_u64;
+        let mut counts = (header_read, 0_u64);
+        let mut write_ready = |result: ChunkResult| -> Result<(), SieveError> {
+            let (index, output, (read, removed)) = result?;
+            pending.insert(index, output);
+            while let Some(output) = pending.remove(&next_to_write) {
+                writer.write_all(&output).map_err(SieveError::Io)?;
+                next_to_write += 1;
+            }
+            counts.0 += read;
+            counts.1 += removed;
+            Ok(())
+        };
+
+        let mut chunks_sent = 0_u64;
+        loop {
+            let mut chunk = Vec::with_capacity(PARALLEL_CHUNK_SIZE);
+            while chunk.len() < PARALLEL_CHUNK_SIZE {
+                let read = reader
+                    .read_until(options.delimiter.0, &mut chunk)
+                    .map_err(read_error)?;
+                if read == 0 {
+                    break;
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            if chunk_sender.send((chunks_sent, chunk)).is_err() {
+                break;
+            }
+            chunks_sent += 1;
+            while let Ok(result) = result_receiver.try_recv() {
+                write_ready(result)?;
+            }
+        }
+        // Closing the queue lets the workers finish once it's drained
+        drop(chunk_sender);
+        for result in result_receiver {
+            write_ready(result)?;
+        }
+        Ok(counts)
+    })
+}
+
+/// The record loop behind [`sieve_stream`], reading each record into `buf`
+fn sieve_records<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    source: &str,
+    matcher: &Matcher,
+    options: &SieveOptions,
+    mut stream: Stream,
+    buf: &mut Vec<u8>,
+) -> Result<(u64, u64), SieveError> {
+    let read_error = |e: std::io::Error| {
+        error!("Failed to read line: {} in {}", e, source);
+        SieveError::LineRead {
+            path: source.to_string(),
+            source: e,
+        }
+    };
+
+    let (skip_lines, header_lines) = if stream.at_start {
+        (options.skip_lines, options.keep_header)
+    } else {
+        (0, 0)
+    };
+    let mut skipped = 0_u64;
+    let mut read_count = 0_u64;
+    let mut dropped_count = 0_u64;
+    let mut collapsed_count = 0_u64;
+    // The last record the filter wrote, which the next kept one is compared against
+    let mut previous: Option<Vec<u8>> = None;
+    let mut unthrottled = 0_usize;
+    loop {
+        if (skipped + read_count).is_multiple_of(CANCEL_CHECK_LINES) && options.is_cancelled() {
+            return Err(SieveError::Cancelled);
+        }
+        buf.clear();
+        if reader
+            .read_until(options.delimiter.0, buf)
+            .map_err(read_error)?
+            == 0
+        {
+            break;
+        }
+        if let Some(throttle) = &options.throttle {
+            unthrottled += buf.len();
+            if unthrottled >= THROTTLE_BATCH {
+                throttle.consume(unthrottled);
+                unthrottled = 0;
+            }
+        }
+        if skipped < skip_lines {
+            writer.write_all(buf).map_err(SieveError::Io)?;
+            skipped += 1;
+            continue;
+        }
+        read_count += 1;
+        if read_count <= header_lines {
+            writer.write_all(buf).map_err(SieveError::Io)?;
+            continue;
+        }
+        let record = strip_line_ending(buf, options.delimiter);
+
+        // Raw bytes are what gets written back; the text is only used for matching
+        let line = if options.binary_safe {
+            String::from_utf8_lossy(record)
+        } else {
+            Cow::Borrowed(
+                std::str::from_utf8(record)
+                    .map_err(|e| read_error(std::io::Error::new(ErrorKind::InvalidData, e)))?,
+            )
+        };
+
+        // With a field selected, the patterns only ever see that part of the line
+        let field = match options.field {
+            Some(field) => field.range(&line),
+            None => Some(0..line.len()),
+        };
+        // Redaction then only touches the trimmed text, so the whitespace is written back too
+        let field = match field {
+            Some(range) if options.trim => Some(trimmed_range(&line, range)),
+            field => field,
+        };
+        let matches = field.clone().map(|range| {
+            let subject = &line[range];
+            match options.pattern_hits.as_ref().filter(|hits| !hits.settled()) {
+                Some(hits) => {
+                    let mut matched = false;
+                    matcher.for_each_match(subject, |index| {
+                        hits.record(index);
+                        matched = true;
+                    });
+                    matched && (!options.match_all || matcher.is_match_all(subject))
+                }
+                None if options.match_all => matcher.is_match_all(subject),
+                None => matcher.is_match(subject),
+            }
+        });
+        let write_line = match matches {
+            None => !options.drop_missing_field,
+            Some(matches) => match options.mode {
+                Mode::Remove => !matches,
+                // With no patterns at all, keeping only the matching lines would empty the file
+                Mode::Keep => matches || options.patterns.is_empty(),
+                Mode::Redact => !matches,
+            },
+        } || options.keep_matcher.as_ref().is_some_and(|keep| {
+            field
+                .clone()
+                .is_some_and(|range| keep.is_match(&line[range]))
+        });
+        if write_line {
+            if options.dedup_consecutive {
+                if previous.as_deref() == Some(record) {
+                    collapsed_count += 1;
+                    if let Some(diff) = stream.diff.as_deref_mut() {
+                        diff.record(skipped + read_count, record, None);
+                    }
+                    continue;
+                }
+                let previous = previous.get_or_insert_with(Vec::new);
+                previous.clear();
+                previous.extend_from_slice(record);
+            }
+            // Write the line back with whatever terminator it had: LF, CRLF or none at all
+            writer.write_all(buf).map_err(SieveError::Io)?;
+        } else {
+            dropped_count += 1;
+            let redacted = if options.mode == Mode::Redact {
+                let redacted = match &field {
+                    Some(range) => match matcher.redact(&line[range.clone()], &options.replacement)
+                    {
+                        Cow::Borrowed(_) => Cow::Borrowed(&*line),
+                        Cow::Owned(redacted) => Cow::Owned(format!(
+                            "{}{redacted}{}",
+                            &line[..range.start],
+                            &line[range.end..]
+                        )),
+                    },
+                    None => Cow::Borrowed(&*line),
+                };
+                writer
+                    .write_all(redacted.as_bytes())
+                    .map_err(SieveError::Io)?;
+                writer
+                    .write_all(&buf[record.len()..])
+                    .map_err(SieveError::Io)?;
+                previous = None;
+                Some(redacted)
+            } else {
+                None
+            };
+            if let Some(diff) = stream.diff.as_deref_mut() {
+                diff.record(
+                    skipped + read_count,
+                    record,
+                    redacted.as_deref().map(str::as_bytes),
+                );
+            }
+            if let Some(on_removed) = &options.on_removed {
+                (on_removed.0)(&line);
+            }
+            if let Some(removed_output) = &options.removed_output {
+                let mut removed_output = removed_output.lock().unwrap();
+                write!(removed_output, "{source}\t").map_err(SieveError::Io)?;
+                removed_output.write_all(record).map_err(SieveError::Io)?;
+                removed_output
+                    .write_all(&[options.delimiter.0])
+                    .map_err(SieveError::Io)?;
+            }
+        }
+    }
+
+    if let Some(throttle) = &options.throttle
+        && unthrottled > 0
+    {
+        throttle.consume(unthrottled);
+    }
+    if let Some(collapsed) = stream.collapsed {
+        *collapsed += collapsed_count;
+    }
+    Ok((read_count, dropped_count))
+}
+
+/// Zero-context unified diff hunks for one file, built up as its records are dropped
+#[derive(Default)]
+struct Diff {
+    hunks: Vec<u8>,
+    /// Old line number of the open hunk's first line, and its `-` and `+` lines
+    start: u64,
+    removed: Vec<u8>,
+    removed_count: u64,
+    added: Vec<u8>,
+    added_count: u64,
+    /// How many lines shorter the new file is than the old one before the open hunk
+    shrunk_by: u64,
+}
+
+impl Diff {
+    /// Add the record at `line_number` (counting from 1), replaced by `replacement` if
+    /// it was redacted rather than removed
+    fn record(&mut self, line_number: u64, record: &[u8], replacement: Option<&[u8]>) {
+        if self.removed_count > 0 && self.start + self.removed_count != line_number {
+            self.close_hunk();
+        }
+        if self.removed_count == 0 {
+            self.start = line_number;
+        }
+        Self::push_line(&mut self.removed, b'-', record);
+        self.removed_count += 1;
+        if let Some(replacement) = replacement {
+            Self::push_line(&mut self.added, b'+', replacement);
+            self.added_count += 1;
+        }
+    }
+
+    fn push_line(lines: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+        lines.push(prefix);
+        lines.extend_from_slice(line);
+        lines.push(b'\n');
+    }
+
+    fn close_hunk(&mut self) {
+        if self.removed_count == 0 {
+            return;
+        }
+        // An empty range names the line just before it
+        let new_start = self.start - self.shrunk_by - u64::from(self.added_count == 0);
+        let header = format!(
+            "@@ -{},{} +{},{} @@\n",
+            self.start, self.removed_count, new_start, self.added_count
+        );
+        self.hunks.extend_from_slice(header.as_bytes());
+        self.hunks.append(&mut self.removed);
+        self.hunks.append(&mut self.added);
+        self.shrunk_by += self.removed_count - self.added_count;
+        self.removed_count = 0;
+        self.added_count = 0;
+    }
+
+    /// Write the whole diff for `source`, headers first
+    fn write_to(mut self, out: &mut dyn Write, source: &str) -> Result<(), SieveError> {
+        self.close_hunk();
+        writeln!(out, "--- {source}\n+++ {source}")?;
+        out.write_all(&self.hunks)?;
+        Ok(())
+    }
+}
+
+/// The record without its trailing delimiter; for newlines a preceding `\r` goes too,
+/// matching what `BufRead::lines` yields
+fn strip_line_ending(line: &[u8], delimiter: Delimiter) -> &[u8] {
+    let line = line.strip_suffix(&[delimiter.0]).unwrap_or(line);
+    if delimiter.0 == b'\n' {
+        line.strip_suffix(b"\r").unwrap_or(line)
+    } else {
+        line
+    }
+}
+
+/// Create a temp file in the target's directory so it can later be renamed into place.
+/// Falls back to the system temp directory if the target directory isn't writable.
+fn create_temp_file_near(target: &Path) -> Result<NamedTempFile, SieveError> {
+    let dir = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    NamedTempFile::new_in(dir)
+        .or_else(|_| NamedTempFile::new())
+        .map_err(SieveError::Io)
+}
+
+/// Read the finished output at `path` back through its codec to make sure it
+/// decompresses cleanly; `target` is the file it's meant to replace
+fn verify_output(path: &Path, codec: Codec, target: &Path) -> Result<(), SieveError> {
+    let failed = |e: std::io::Error| {
+        SieveError::Processing(format!(
+            "Output for {} failed verification, leaving it untouched: {e}",
+            target.display()
+        ))
+    };
+    let file = File::open(path).map_err(failed)?;
+    std::io::copy(&mut codec.reader(file)?, &mut std::io::sink()).map_err(failed)?;
+    Ok(())
+}
+
+/// Added to a file's name for the partial output kept by
+/// [`SieveOptions::keep_temp_on_error`]
+pub const PARTIAL_SUFFIX: &str = ".sieve-partial";
+
+/// Keep what had been written to `target`'s temp file when `error` stopped it, under
+/// `target`'s name plus [`PARTIAL_SUFFIX`]. Failing to keep it is only logged, so the
+/// file still fails with `error`.
+fn keep_partial(
+    temp_file: Option<NamedTempFile>,
+    writer: Box<dyn Write>,
+    target: &Path,
+    error: &SieveError,
+) {
+    // Nothing is written in a dry run, and a cancelled file didn't go wrong
+    let Some(temp_file) = temp_file else {
+        return;
+    };
+    if matches!(error, SieveError::Cancelled) {
+        return;
+    }
+    // Finishing the encoder leaves the partial output readable with the usual tools
+    drop(writer);
+    let mut partial = target.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    let partial = PathBuf::from(partial);
+    match temp_file.persist(&partial) {
+        Ok(_) => warn!(
+            "Kept the partial output for {} at {}",
+            target.display(),
+            partial.display()
+        ),
+        Err(e) => warn!(
+            "Failed to keep the partial output for {} at {}: {}",
+            target.display(),
+            partial.display(),
+            e.error
+        ),
+    }
+}
+
+/// Atomically rename the finished temp file over `target`, so a crash never leaves a
+/// half-written file behind. A plain copy is used when the two are on different devices.
+/// With `fsync`, the data is on disk before the rename and the rename itself after it.
+fn replace_file(
+    temp_file: NamedTempFile,
+    target: &Path,
+    force: bool,
+    fsync: bool,
+) -> Result<(), SieveError> {
+    // Flushed before the rename, so a crash can't leave the target naming an empty file
+    if fsync {
+        temp_file.as_file().sync_all()?;
+    }
+    move_into_place(temp_file, target, force)?;
+    if fsync {
+        sync_to_disk(target)?;
+    }
+    Ok(())
+}
+
+/// Flush `path` and, on Unix, the directory entry naming it to disk
+fn sync_to_disk(path: &Path) -> std::io::Result<()> {
+    // Only needed after a copy, which bypasses the synced temp file
+    File::open(path)?.sync_all()?;
+    // Windows can't open a directory as a file to sync it
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The rename (or copy) behind [`replace_file`]
+fn move_into_place(temp_file: NamedTempFile, target: &Path, force: bool) -> Result<(), SieveError> {
+    let failure = match temp_file.persist(target) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    match failure.error.kind() {
+        ErrorKind::CrossesDevices => copy_file_into_place(failure.file.path(), target, force),
+        ErrorKind::PermissionDenied if force => {
+            let temp_file = failure.file;
+            with_write_permission(target, || {
+                temp_file.persist(target).map(drop).map_err(|e| e.error)
+            })
+            .map_err(|e| {
+                SieveError::Processing(format!("Failed to replace {}: {e}", target.display()))
+            })
+        }
+        _ => Err(SieveError::Processing(format!(
+            "Failed to replace {}: {}",
+            target.display(),
+            failure.error
+        ))),
+    }
+}
+
+/// Slower, non-atomic replacement used when a rename isn't possible: the whole
+/// file is read and written a second time, and a crash mid-copy tears the target.
+fn copy_file_into_place(source: &Path, target: &Path, force: bool) -> Result<(), SieveError> {
+    let result = match copy(source, target) {
+        Err(e) if force && e.kind() == ErrorKind::PermissionDenied => {
+            with_write_permission(target, || copy(source, target).map(drop))
+        }
+        result => result.map(drop),
+    };
+    result.map_err(|e| {
+        SieveError::Processing(format!(
+            "Failed to copy {} across devices: {e}",
+            target.display()
+        ))
+    })
+}
+
+/// Retry a write with the target's read-only protection lifted, putting the
+/// original permissions back afterwards whether or not the write succeeded
+fn with_write_permission(
+    target: &Path,
+    write: impl FnOnce() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let original = std::fs::metadata(target)?.permissions();
+    let mut writable = original.clone();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        writable.set_mode(writable.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    writable.set_readonly(false);
+    std::fs::set_permissions(target, writable)?;
+
+    let result = write();
+    std::fs::set_permissions(target, original)?;
+    result
+}
+
+/// Copy the original file alongside itself with `suffix` appended to the name.
+/// An existing backup is never overwritten: it is either kept or reported as an error.
+fn backup_file(file_path: &Path, suffix: &str, keep_existing: bool) -> Result<(), SieveError> {
+    let mut backup_name = file_path.as_os_str().to_owned();
+    backup_name.push(suffix);
+    let backup_path = PathBuf::from(backup_name);
+
+    if backup_path.exists() {
+        if keep_existing {
+            debug!("Keeping existing backup {}", backup_path.display());
+            return Ok(());
+        }
+        return Err(SieveError::BackupExists(backup_path.display().to_string()));
+    }
+
+    copy(file_path, &backup_path)
+        .map_err(|e| SieveError::Processing(format!("Failed to back up original file: {e}")))?;
+    Ok(())
+}
+
+/// Give the rewritten file the original's access and modification times
+fn restore_times(path: &Path, metadata: &Metadata) -> Result<(), SieveError> {
+    filetime::set_file_times(
+        path,
+        FileTime::from_last_access_time(metadata),
+        FileTime::from_last_modification_time(metadata),
+    )?;
+    Ok(())
+}
@@ -1,23 +1,72 @@
 use super::*;
+use crate::cli::{Args, EXIT_LINES_REMOVED, OutputFormat};
+use clap::Parser;
 use flate2::Compression;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use num_format::{Locale, ToFormattedString};
 use std::io::Write;
+use std::time::Duration;
 use tempfile::tempdir;
 
+/// Write the given lines to a new gzipped file
+fn write_gz_lines(file_path: &Path, lines: &[&str]) {
+    let file = File::create(file_path).unwrap();
+    let gz = GzEncoder::new(file, Compression::default());
+    let mut writer = BufWriter::new(gz);
+    for line in lines {
+        writeln!(writer, "{line}").unwrap();
+    }
+}
+
+/// Read all lines back out of a gzipped file
+fn read_gz_lines(file_path: &Path) -> Vec<String> {
+    let file = File::open(file_path).unwrap();
+    let reader = BufReader::new(GzDecoder::new(file));
+    reader.lines().map(|l| l.unwrap()).collect()
+}
+
 #[test]
 fn test_gather_gz_files() {
     let dir = tempdir().unwrap();
     let file_path = dir.path().join("test.gz");
     File::create(&file_path).unwrap();
 
-    let (files, total_size) = gather_gz_files(dir.path());
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
     assert_eq!(files.len(), 1);
     assert_eq!(files[0].0, file_path);
     assert_eq!(total_size, 0);
 }
 
+#[test]
+fn test_sieveignore_excludes_files() {
+    let dir = tempdir().unwrap();
+    let sub = dir.path().join("archive");
+    std::fs::create_dir(&sub).unwrap();
+    for path in [
+        dir.path().join("keep.gz"),
+        dir.path().join("skip.gz"),
+        sub.join("keep.gz"),
+        sub.join("old.gz"),
+    ] {
+        File::create(path).unwrap();
+    }
+    std::fs::write(dir.path().join(IGNORE_FILE_NAME), "skip.gz\n").unwrap();
+    // Relative to its own directory, so the root's old.gz wouldn't be affected
+    std::fs::write(sub.join(IGNORE_FILE_NAME), "/old.gz\n").unwrap();
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    let mut found: Vec<_> = files
+        .iter()
+        .map(|(path, _)| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+        .collect();
+    found.sort();
+    assert_eq!(
+        found,
+        vec![PathBuf::from("archive/keep.gz"), PathBuf::from("keep.gz")]
+    );
+}
+
 #[test]
 fn test_filter_lines() {
     let dir = tempdir().unwrap();
@@ -34,7 +83,12 @@ fn test_filter_lines() {
     }
 
     let patterns = vec!["pattern".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 3);
     assert_eq!(removed, 1);
@@ -50,7 +104,7 @@ fn test_filter_lines() {
 
 #[test]
 fn test_locale_es_formatting() {
-    let locale_es = super::get_locale("es");
+    let locale_es = cli::get_locale("es");
     // Spanish (es) locale, the expected format for a number like 12,345.67 (en) is "12.345,67".
     assert_eq!(locale_es.decimal(), ",");
     assert_eq!(locale_es.separator(), ".");
@@ -63,7 +117,7 @@ fn test_locale_es_formatting() {
 
 #[test]
 fn test_locale_en_formatting() {
-    let locale_en = super::get_locale("en");
+    let locale_en = cli::get_locale("en");
     assert_eq!(locale_en.decimal(), ".");
     assert_eq!(locale_en.separator(), ",");
 
@@ -89,7 +143,12 @@ fn test_no_patterns() {
     }
 
     let patterns: Vec<String> = vec![];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 3);
     assert_eq!(removed, 0);
@@ -119,7 +178,12 @@ fn test_non_existent_patterns() {
     }
 
     let patterns = vec!["nonexistent".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 3);
     assert_eq!(removed, 0);
@@ -149,7 +213,12 @@ fn test_special_characters_in_patterns() {
     }
 
     let patterns = vec!["special*chars".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 3);
     assert_eq!(removed, 1);
@@ -178,7 +247,12 @@ fn test_empty_files() {
     }
 
     let patterns = vec!["pattern".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 0);
     assert_eq!(removed, 0);
@@ -200,7 +274,12 @@ fn test_large_patterns_list() {
     }
 
     let patterns: Vec<String> = (0..1000).map(|i| format!("pattern{}", i)).collect();
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 3);
     assert_eq!(removed, 0);
@@ -232,7 +311,12 @@ fn test_nested_directories() {
     }
 
     let patterns = vec!["pattern".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 3);
     assert_eq!(removed, 1);
@@ -267,11 +351,75 @@ fn test_read_only_files() {
     std::fs::set_permissions(&file_path, perms).unwrap();
 
     let patterns = vec!["pattern".to_string()];
-    let result = filter_lines(&file_path, &patterns, &Mode::Remove);
+    let result = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    );
 
     assert!(result.is_err());
 }
 
+#[test]
+fn test_force_rewrites_read_only_files() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("readonly.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "line 3"]);
+
+    let mut perms = std::fs::metadata(&file_path).unwrap().permissions();
+    perms.set_readonly(true);
+    std::fs::set_permissions(&file_path, perms).unwrap();
+
+    let patterns = vec!["pattern".to_string()];
+    let options = SieveOptions {
+        force: true,
+        ..Default::default()
+    };
+    filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &options,
+    )
+    .unwrap();
+
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1", "line 3"]);
+    assert!(
+        std::fs::metadata(&file_path)
+            .unwrap()
+            .permissions()
+            .readonly()
+    );
+}
+
+#[test]
+fn test_with_write_permission_restores_permissions() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("readonly.txt");
+    std::fs::write(&file_path, "old").unwrap();
+    let mut perms = std::fs::metadata(&file_path).unwrap().permissions();
+    perms.set_readonly(true);
+    std::fs::set_permissions(&file_path, perms).unwrap();
+
+    super::with_write_permission(&file_path, || std::fs::write(&file_path, "new")).unwrap();
+    assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "new");
+    assert!(
+        std::fs::metadata(&file_path)
+            .unwrap()
+            .permissions()
+            .readonly()
+    );
+
+    // Errors from the write itself are passed through untouched
+    let result = super::with_write_permission(&file_path, || Err(std::io::Error::other("boom")));
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::Other);
+    assert!(
+        std::fs::metadata(&file_path)
+            .unwrap()
+            .permissions()
+            .readonly()
+    );
+}
+
 #[test]
 fn test_files_of_different_compression_levels() {
     let dir = tempdir().unwrap();
@@ -289,7 +437,12 @@ fn test_files_of_different_compression_levels() {
         }
 
         let patterns = vec!["pattern".to_string()];
-        let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+        let FileResult { read, removed, .. } = filter_lines(
+            &file_path,
+            &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+            &SieveOptions::default(),
+        )
+        .unwrap();
 
         assert_eq!(read, 3);
         assert_eq!(removed, 1);
@@ -319,7 +472,12 @@ fn test_files_containing_only_patterns() {
     }
 
     let patterns = vec!["pattern".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 2);
     assert_eq!(removed, 2);
@@ -347,17 +505,456 @@ fn test_files_containing_binary_data() {
     }
 
     let patterns = vec!["pattern".to_string()];
-    let result = filter_lines(&file_path, &patterns, &Mode::Remove);
+    let result = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    );
 
     // With our improved error handling, this should now return an error
     // instead of silently returning (0, 0)
     assert!(result.is_err());
 }
 
+#[test]
+fn test_binary_safe_preserves_invalid_utf8_lines() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("binary.gz");
+
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(b"ok line\n").unwrap();
+        gz.write_all(b"bad \xff\xfe bytes\n").unwrap();
+        gz.write_all(b"remove me\n").unwrap();
+        gz.finish().unwrap();
+    }
+
+    let patterns = vec!["remove".to_string()];
+    let options = SieveOptions {
+        binary_safe: true,
+        ..Default::default()
+    };
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &options,
+    )
+    .unwrap();
+    assert_eq!((read, removed), (3, 1));
+
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(&file_path).unwrap())
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, b"ok line\nbad \xff\xfe bytes\n");
+}
+
+#[test]
+fn test_missing_trailing_newline_is_not_added() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(b"remove me\nfirst\nlast").unwrap();
+        gz.finish().unwrap();
+    }
+
+    let patterns = vec!["remove".to_string()];
+    filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
+
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(&file_path).unwrap())
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, b"first\nlast");
+}
+
+#[test]
+fn test_crlf_line_endings_are_preserved() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("windows.gz");
+
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(b"first\r\nremove me\r\nlast\r\n").unwrap();
+        gz.finish().unwrap();
+    }
+
+    // Exact matching would fail if the `\r` were left on the line
+    let patterns = vec!["remove me".to_string()];
+    filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Exact).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
+
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(&file_path).unwrap())
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, b"first\r\nlast\r\n");
+}
+
+#[test]
+fn test_sieve_stream_filters_in_memory_buffers() {
+    let patterns = vec!["DEBUG".to_string()];
+    let options = SieveOptions::builder(&patterns).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+
+    let input = std::io::Cursor::new(b"INFO start\nDEBUG noise\r\nINFO done".to_vec());
+    let mut output = Vec::new();
+    let counts = sieve_stream(input, &mut output, "memory", &matcher, &options).unwrap();
+
+    assert_eq!(counts, (3, 1));
+    assert_eq!(output, b"INFO start\nINFO done");
+}
+
+#[test]
+fn test_on_removed_is_called_for_every_removed_line() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(
+        &file_path,
+        &["keep", "remove 1", "keep", "remove 2", "remove 3"],
+    );
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_by_callback = Arc::clone(&seen);
+    let options = SieveOptions::builder(["remove"])
+        .on_removed(move |line| seen_by_callback.lock().unwrap().push(line.to_string()))
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len() as u64, result.removed);
+    assert_eq!(*seen, ["remove 1", "remove 2", "remove 3"]);
+}
+
+#[test]
+fn test_record_buffer_is_reused_across_files() {
+    let dir = tempdir().unwrap();
+    let options = SieveOptions::builder(["remove"]).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+
+    let long_line = "x".repeat(4096);
+    let first = dir.path().join("first.gz");
+    write_gz_lines(&first, &[&long_line, "remove me"]);
+    filter_lines(&first, &matcher, &options).unwrap();
+    let buffer = RECORD_BUFFER.take();
+    let (capacity, ptr) = (buffer.capacity(), buffer.as_ptr());
+    RECORD_BUFFER.set(buffer);
+    assert!(capacity > long_line.len());
+
+    // Later files on the same thread fit in the buffer the first one grew
+    for i in 0..200 {
+        let file_path = dir.path().join(format!("small{i}.gz"));
+        write_gz_lines(&file_path, &["keep", "remove me", "keep too"]);
+        filter_lines(&file_path, &matcher, &options).unwrap();
+    }
+    let buffer = RECORD_BUFFER.take();
+    assert_eq!((buffer.capacity(), buffer.as_ptr()), (capacity, ptr));
+}
+
+#[test]
+fn test_redact_replaces_matches_within_lines() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("secrets.gz");
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(b"user=alice key=abc123 key=abc123\r\nnothing here\nkey=abc")
+            .unwrap();
+        gz.finish().unwrap();
+    }
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "abc123",
+        "alice",
+        "--redact",
+        "***",
+    ]);
+    assert_eq!(args.mode(), Mode::Redact);
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&args.patterns, args.match_kind()).unwrap(),
+        &args.sieve_options(dir.path()).unwrap(),
+    )
+    .unwrap();
+    // The count of changed lines is what gets reported as redacted
+    assert_eq!((read, removed), (3, 1));
+
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(&file_path).unwrap())
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(
+        content,
+        b"user=*** key=*** key=***\r\nnothing here\nkey=abc"
+    );
+}
+
+#[test]
+fn test_redact_with_regex_patterns() {
+    let matcher = Matcher::new(&[r"[\w.]+@[\w.]+".to_string()], MatchKind::Regex).unwrap();
+    assert_eq!(
+        matcher.redact("from a.b@example.com to c@d.org", "<email>"),
+        "from <email> to <email>"
+    );
+    assert_eq!(matcher.redact("x@y.z", "$$"), "$");
+    assert!(matches!(
+        matcher.redact("no address", "-"),
+        Cow::Borrowed(_)
+    ));
+
+    // Capture groups keep part of each match
+    let matcher = Matcher::new(&[r"\b\d{12}(\d{4})\b".to_string()], MatchKind::Regex).unwrap();
+    assert_eq!(
+        matcher.redact("card 4111111111111111 ok", "************${1}"),
+        "card ************1111 ok"
+    );
+    let matcher = Matcher::new(
+        &[r"(?P<user>\w+)@(?P<domain>[\w.]+)".to_string()],
+        MatchKind::Regex,
+    )
+    .unwrap();
+    assert_eq!(
+        matcher.redact("to bob@example.com", "***@${domain}"),
+        "to ***@example.com"
+    );
+
+    let matcher = Matcher::new(&["GET".to_string()], MatchKind::StartsWith).unwrap();
+    assert_eq!(matcher.redact("GET /GET", "-"), "- /GET");
+}
+
+#[test]
+fn test_nul_delimited_records() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("records.gz");
+
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(b"first\nrecord\0remove me\0last\0").unwrap();
+        gz.finish().unwrap();
+    }
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "remove",
+        "--delimiter",
+        "\\0",
+    ]);
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&args.patterns, args.match_kind()).unwrap(),
+        &args.sieve_options(dir.path()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!((read, removed), (3, 1));
+
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(&file_path).unwrap())
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, b"first\nrecord\0last\0");
+}
+
+#[test]
+fn test_parse_delimiter() {
+    assert_eq!(cli::parse_delimiter("\\0"), Ok(Delimiter(0)));
+    assert_eq!(cli::parse_delimiter("\\t"), Ok(Delimiter(b'\t')));
+    assert_eq!(cli::parse_delimiter("|"), Ok(Delimiter(b'|')));
+    assert!(cli::parse_delimiter("ab").is_err());
+    assert!(cli::parse_delimiter("é").is_err());
+}
+
+#[test]
+fn test_delete_emptied_removes_file() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["drop one", "drop two"]);
+
+    let patterns = vec!["drop".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+
+    // A dry run only reports the deletion
+    let dry_run = SieveOptions {
+        delete_emptied: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    let result = filter_lines(&file_path, &matcher, &dry_run).unwrap();
+    assert_eq!((result.read, result.removed), (2, 2));
+    assert!(file_path.exists());
+
+    let options = SieveOptions {
+        delete_emptied: true,
+        ..Default::default()
+    };
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!((result.read, result.removed), (2, 2));
+    assert_eq!(result.bytes_after, 0);
+    assert!(!file_path.exists());
+}
+
+#[test]
+fn test_delete_emptied_keeps_partially_filtered_file() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["drop one", "keep"]);
+
+    let patterns = vec!["drop".to_string()];
+    let options = SieveOptions {
+        delete_emptied: true,
+        ..Default::default()
+    };
+    filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(read_gz_lines(&file_path), vec!["keep"]);
+}
+
+#[test]
+fn test_count_only_writes_nothing() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["drop one", "keep", "drop two"]);
+    let original = std::fs::read(&file_path).unwrap();
+    let original_modified = std::fs::metadata(&file_path).unwrap().modified().unwrap();
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--count-only",
+    ]);
+    let counts = filter_lines(
+        &file_path,
+        &Matcher::new(&args.patterns, args.match_kind()).unwrap(),
+        &args.sieve_options(dir.path()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!((counts.read, counts.removed), (3, 2));
+
+    // The file is untouched and nothing else appeared next to it
+    assert_eq!(std::fs::read(&file_path).unwrap(), original);
+    assert_eq!(
+        std::fs::metadata(&file_path).unwrap().modified().unwrap(),
+        original_modified
+    );
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_emptied_file_is_rewritten_by_default() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["drop one", "drop two"]);
+
+    let patterns = vec!["drop".to_string()];
+    filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
+    assert!(read_gz_lines(&file_path).is_empty());
+}
+
+#[test]
+fn test_no_empty_leaves_file_untouched() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["drop one", "drop two"]);
+
+    let patterns = vec!["drop".to_string()];
+    let options = SieveOptions {
+        no_empty: true,
+        ..Default::default()
+    };
+    let counts = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &options,
+    )
+    .unwrap();
+    assert_eq!((counts.read, counts.removed), (2, 2));
+    assert_eq!(read_gz_lines(&file_path), vec!["drop one", "drop two"]);
+}
+
+#[test]
+fn test_no_empty_conflicts_with_delete_emptied() {
+    let result = Args::try_parse_from(["sieve", ".", "x", "--no-empty", "--delete-emptied"]);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_max_removal_ratio() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    let lines = ["drop 1", "drop 2", "keep 1", "keep 2"];
+    write_gz_lines(&file_path, &lines);
+
+    let patterns = vec!["drop".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+
+    // Above the threshold the original is left alone
+    let strict = SieveOptions {
+        max_removal_ratio: Some(0.25),
+        ..Default::default()
+    };
+    let result = filter_lines(&file_path, &matcher, &strict);
+    assert!(matches!(
+        result,
+        Err(SieveError::RemovalRatioExceeded {
+            dropped: 2,
+            read: 4,
+            ..
+        })
+    ));
+    assert_eq!(read_gz_lines(&file_path), lines);
+
+    // Exactly at the threshold is still allowed
+    let at_limit = SieveOptions {
+        max_removal_ratio: Some(0.5),
+        ..Default::default()
+    };
+    let result = filter_lines(&file_path, &matcher, &at_limit).unwrap();
+    assert_eq!((result.read, result.removed), (4, 2));
+    assert_eq!(read_gz_lines(&file_path), vec!["keep 1", "keep 2"]);
+}
+
+#[test]
+fn test_parse_ratio() {
+    assert_eq!(cli::parse_ratio("0.5"), Ok(0.5));
+    assert_eq!(cli::parse_ratio("1"), Ok(1.0));
+    assert!(cli::parse_ratio("1.5").is_err());
+    assert!(cli::parse_ratio("-0.1").is_err());
+    assert!(cli::parse_ratio("half").is_err());
+}
+
 #[test]
 fn test_empty_directory() {
     let dir = tempdir().unwrap();
-    let (files, total_size) = gather_gz_files(dir.path());
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
     assert!(files.is_empty());
     assert_eq!(total_size, 0);
 }
@@ -379,7 +976,12 @@ fn test_multiple_patterns() {
     }
 
     let patterns = vec!["pattern1".to_string(), "pattern2".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 4);
     assert_eq!(removed, 2);
@@ -415,7 +1017,12 @@ fn test_large_file() {
     }
 
     let patterns = vec!["remove".to_string()];
-    let (read, removed) = filter_lines(&file_path, &patterns, &Mode::Remove).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    )
+    .unwrap();
 
     assert_eq!(read, 1000);
     assert_eq!(removed, 100); // Every 10th line should be removed
@@ -433,14 +1040,18 @@ fn test_invalid_gz_file() {
     }
 
     let patterns = vec!["pattern".to_string()];
-    let result = filter_lines(&file_path, &patterns, &Mode::Remove);
+    let result = filter_lines(
+        &file_path,
+        &Matcher::new(&patterns, MatchKind::Contains).unwrap(),
+        &SieveOptions::default(),
+    );
     assert!(result.is_err());
 }
 
 #[test]
 fn test_parse_args() {
     // Test with specific arguments
-    let args = super::parse_args_from(vec![
+    let args = cli::parse_args_from(vec![
         "sieve",    // program name
         "/tmp",     // root_dir
         "pattern1", // patterns
@@ -454,30 +1065,157 @@ fn test_parse_args() {
     ]);
 
     // Verify the arguments were parsed correctly
-    assert_eq!(args.root_dir, "/tmp");
+    assert_eq!(args.root_dir.as_deref(), Some("/tmp"));
     assert_eq!(args.patterns, vec!["pattern1", "pattern2"]);
     assert_eq!(args.threads, Some(4));
-    assert_eq!(args.log_output, super::LogOutput::Stdout);
-    assert_eq!(args.locale, "fr");
+    assert_eq!(args.log_output, cli::LogOutput::Stdout);
+    assert_eq!(args.locale.as_deref(), Some("fr"));
 
     // Test with minimal arguments
-    let args = super::parse_args_from(vec!["sieve", "/tmp", "pattern1"]);
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "pattern1"]);
 
     // Verify defaults are applied
-    assert_eq!(args.root_dir, "/tmp");
+    assert_eq!(args.root_dir.as_deref(), Some("/tmp"));
     assert_eq!(args.patterns, vec!["pattern1"]);
     assert_eq!(args.threads, None);
-    assert_eq!(args.log_output, super::LogOutput::File); // default
-    assert_eq!(args.locale, "en"); // default
+    assert_eq!(args.log_output, cli::LogOutput::File); // default
+    assert_eq!(args.locale, None); // taken from the environment
+}
+
+#[test]
+fn test_config_file_provides_defaults() {
+    let dir = tempdir().unwrap();
+    let config = dir.path().join(cli::CONFIG_FILE_NAME);
+    std::fs::write(
+        &config,
+        "patterns = [\"DEBUG\", \"TRACE\"]\nthreads = 3\nlocale = \"de\"\nlog-output = \"stdout\"\nmin-size = \"1K\"\nregex = true\n",
+    )
+    .unwrap();
+
+    // Config only
+    let args = cli::parse_args_with_config(["sieve", "/tmp"], &config).unwrap();
+    assert_eq!(args.patterns, ["DEBUG", "TRACE"]);
+    assert_eq!(args.threads, Some(3));
+    assert_eq!(args.locale.as_deref(), Some("de"));
+    assert_eq!(args.log_output, cli::LogOutput::Stdout);
+    assert_eq!(args.min_size, Some(1024));
+    assert_eq!(args.match_kind(), MatchKind::Regex);
+
+    // CLI only: no config file to find
+    let missing = dir.path().join("missing.toml");
+    let args =
+        cli::parse_args_with_config(["sieve", "/tmp", "INFO", "--threads", "2"], &missing).unwrap();
+    assert_eq!(args.patterns, ["INFO"]);
+    assert_eq!(args.threads, Some(2));
+    assert_eq!(args.locale, None);
+    assert_eq!(args.match_kind(), MatchKind::Contains);
+}
+
+#[test]
+fn test_command_line_overrides_config_file() {
+    let dir = tempdir().unwrap();
+    let config = dir.path().join("custom.toml");
+    std::fs::write(
+        &config,
+        "patterns = [\"DEBUG\"]\nthreads = 3\nregex = true\n",
+    )
+    .unwrap();
+
+    let args = cli::parse_args_with_config(
+        [
+            "sieve",
+            "/tmp",
+            "INFO",
+            "--threads",
+            "8",
+            "--exact",
+            "--config",
+            &config.to_string_lossy(),
+        ],
+        &dir.path().join(cli::CONFIG_FILE_NAME),
+    )
+    .unwrap();
+    assert_eq!(args.patterns, ["INFO"]);
+    assert_eq!(args.threads, Some(8));
+    // --exact conflicts with the config's regex, so the config value is dropped
+    assert_eq!(args.match_kind(), MatchKind::Exact);
+
+    // Conflicting matchers from the config alone are an error, as they are as flags
+    std::fs::write(&config, "regex = true\nexact = true\n").unwrap();
+    let result = cli::parse_args_with_config(
+        ["sieve", "/tmp", "--config", &config.to_string_lossy()],
+        &config,
+    );
+    assert!(matches!(result, Err(SieveError::InvalidOptions(_))));
+    // Unless the command line picks one
+    let args = cli::parse_args_with_config(
+        [
+            "sieve",
+            "/tmp",
+            "--exact",
+            "--config",
+            &config.to_string_lossy(),
+        ],
+        &config,
+    )
+    .unwrap();
+    assert_eq!(args.match_kind(), MatchKind::Exact);
+
+    std::fs::write(&config, "unknown-key = 1\n").unwrap();
+    let result = cli::parse_args_with_config(
+        ["sieve", "/tmp", "--config", &config.to_string_lossy()],
+        &config,
+    );
+    assert!(matches!(result, Err(SieveError::InvalidOptions(_))));
+}
+
+#[test]
+fn test_generate_completions() {
+    // No root directory is needed just to print completions
+    let args = cli::parse_args_from(vec!["sieve", "--generate-completions", "bash"]);
+    assert_eq!(args.generate_completions, Some(clap_complete::Shell::Bash));
+
+    let mut out = Vec::new();
+    cli::write_completions(clap_complete::Shell::Bash, &mut out).unwrap();
+    let script = String::from_utf8(out).unwrap();
+    assert!(script.contains("_sieve()"));
+    assert!(script.contains("--max-removal-ratio"));
+    assert!(script.contains("complete -F _sieve"));
+}
+
+#[test]
+fn test_locale_from_env() {
+    let env = |vars: &'static [(&'static str, &'static str)]| {
+        move |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    };
+    assert_eq!(
+        cli::locale_from_env(env(&[("LC_ALL", "fr_FR.UTF-8"), ("LANG", "de_DE")])),
+        Some("fr".to_string())
+    );
+    assert_eq!(
+        cli::locale_from_env(env(&[("LC_ALL", ""), ("LANG", "de_CH.UTF-8")])),
+        Some("de-CH".to_string())
+    );
+    assert_eq!(cli::locale_from_env(env(&[("LANG", "C.UTF-8")])), None);
+    assert_eq!(cli::locale_from_env(env(&[("LANG", "xx_YY")])), None);
+    assert_eq!(cli::locale_from_env(env(&[])), None);
+
+    // An explicit --locale wins over the environment
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "x", "--locale", "es"]);
+    assert_eq!(args.locale(), "es");
 }
 
 #[test]
 fn test_get_locale() {
-    assert_eq!(super::get_locale("en"), Locale::en);
-    assert_eq!(super::get_locale("fr"), Locale::fr);
-    assert_eq!(super::get_locale("de"), Locale::de);
-    assert_eq!(super::get_locale("ja"), Locale::ja);
-    assert_eq!(super::get_locale("invalid"), Locale::en); // default
+    assert_eq!(cli::get_locale("en"), Locale::en);
+    assert_eq!(cli::get_locale("fr"), Locale::fr);
+    assert_eq!(cli::get_locale("de"), Locale::de);
+    assert_eq!(cli::get_locale("ja"), Locale::ja);
+    assert_eq!(cli::get_locale("invalid"), Locale::en); // default
 }
 
 #[test]
@@ -489,7 +1227,7 @@ fn test_cleanup_empty_log_file() {
     File::create(&file_path).unwrap();
 
     // Run the cleanup function
-    let result = super::cleanup_empty_log_file(&file_path.to_string_lossy());
+    let result = cli::cleanup_empty_log_file(&file_path.to_string_lossy());
 
     // Should succeed
     assert!(result.is_ok());
@@ -502,7 +1240,7 @@ fn test_cleanup_empty_log_file() {
     let mut file = File::create(&file_path).unwrap();
     file.write_all(b"some content").unwrap();
 
-    let result = super::cleanup_empty_log_file(&file_path.to_string_lossy());
+    let result = cli::cleanup_empty_log_file(&file_path.to_string_lossy());
 
     // Should succeed
     assert!(result.is_ok());
@@ -514,7 +1252,7 @@ fn test_cleanup_empty_log_file() {
 #[test]
 fn test_setup_logging() {
     // Test stdout logging
-    let result = super::setup_logging(&super::LogOutput::Stdout);
+    let result = cli::setup_logging(&cli::LogOutput::Stdout, &cli::LogFormat::Text);
     assert!(result.is_ok());
     let log_file_name = result.unwrap();
     assert!(log_file_name.is_none());
@@ -524,42 +1262,448 @@ fn test_setup_logging() {
     // implementation of the logger
 }
 
-#[test]
-fn test_print_summary() {
-    // This function only prints to stdout, so we just ensure it doesn't panic
-    super::print_summary(100, 10, &Mode::Remove, "en");
-    super::print_summary(100, 10, &Mode::Keep, "fr");
-    super::print_summary(100, 10, &Mode::Remove, "invalid");
-}
+/// Logger that keeps each record's level and message for inspection
+#[derive(Default)]
+struct CaptureLogger(Mutex<Vec<String>>);
 
-#[test]
-fn test_process_files() {
-    // Create a test directory with some files
-    let dir = tempdir().unwrap();
-    let file_path = dir.path().join("test.gz");
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
 
-    // Create a gzipped file with some content
-    {
-        let file = File::create(&file_path).unwrap();
-        let gz = GzEncoder::new(file, Compression::default());
-        let mut writer = BufWriter::new(gz);
-        writeln!(writer, "line 1").unwrap();
-        writeln!(writer, "line 2 pattern").unwrap();
-        writeln!(writer, "line 3").unwrap();
+    fn log(&self, record: &log::Record) {
+        let line = format!("{} {}", record.level(), record.args());
+        self.0.lock().unwrap().push(line);
     }
 
-    // Get file size for progress tracking
+    fn flush(&self) {}
+}
+
+#[test]
+fn test_summary_is_logged() {
+    let summary = RunSummary {
+        total_lines_read: 1_234_567,
+        total_lines_removed: 4_321,
+        files_processed: 1_200,
+        files_failed: 2,
+        ..Default::default()
+    };
+    let logger = CaptureLogger::default();
+    cli::log_summary(&logger, &summary, &Mode::Remove, "de");
+
+    assert_eq!(
+        *logger.0.lock().unwrap(),
+        [
+            "INFO Removed 4.321 lines from a total of 1.234.567 lines read. 1.200 files processed, 0 skipped, 2 failed."
+        ]
+    );
+}
+
+/// Writer whose output stays readable after it's been handed off as a boxed target
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_json_log_format() {
+    let buffer = SharedBuffer::default();
+    let logger = cli::logger_builder(&cli::LogFormat::Json)
+        .filter_level(log::LevelFilter::Info)
+        .target(env_logger::Target::Pipe(Box::new(buffer.clone())))
+        .build();
+    log::Log::log(
+        &logger,
+        &log::Record::builder()
+            .level(log::Level::Warn)
+            .target("sieve")
+            .file(Some("src/lib.rs"))
+            .line(Some(42))
+            .args(format_args!("Skipping {}", "a.gz"))
+            .build(),
+    );
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let record: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+    assert_eq!(record["level"], "WARN");
+    assert_eq!(record["message"], "Skipping a.gz");
+    assert_eq!(record["target"], "sieve");
+    assert_eq!(record["file"], "src/lib.rs");
+    assert_eq!(record["line"], 42);
+    assert!(record["timestamp"].is_string());
+}
+
+#[test]
+fn test_print_summary() {
+    let summary = RunSummary {
+        total_lines_read: 100,
+        total_lines_removed: 10,
+        files_processed: 1,
+        ..Default::default()
+    };
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "Removed 10 lines from a total of 100 lines read.\n1 files processed, 0 skipped, 0 failed.\n"
+    );
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Keep, "fr").unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "Kept 90 lines from a total of 100 lines read.\n1 files processed, 0 skipped, 0 failed.\n"
+    );
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Redact, "en").unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "Redacted 10 lines from a total of 100 lines read.\n1 files processed, 0 skipped, 0 failed.\n"
+    );
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "invalid").unwrap();
+    assert!(!out.is_empty());
+}
+
+#[test]
+fn test_pattern_hit_counts() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(
+        &file_path,
+        &["DEBUG a", "DEBUG b", "INFO c", "WARN DEBUG d", "ERROR e"],
+    );
+    let files = vec![(file_path, 0)];
+
+    let options = SieveOptions::builder(["DEBUG", "WARN", "TRACE"])
+        .pattern_hits(true)
+        .build()
+        .unwrap();
+    let summary = process_files(&files, &options, 0, &RunOptions::default()).unwrap();
+    assert_eq!(summary.total_lines_removed, 3);
+    assert_eq!(
+        summary.pattern_hits,
+        [
+            ("DEBUG".to_string(), 3),
+            ("WARN".to_string(), 1),
+            ("TRACE".to_string(), 0)
+        ]
+    );
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("Lines matched per pattern:\n           3  DEBUG\n           1  WARN\n"));
+    assert!(out.contains("           0  TRACE  (never matched, potentially removable)\n"));
+
+    // A pattern found only inside a longer one still counts
+    let file_path = dir.path().join("overlap.gz");
+    write_gz_lines(&file_path, &["foobar x", "bar y"]);
+    let options = SieveOptions::builder(["foo", "foobar"])
+        .pattern_hits(true)
+        .build()
+        .unwrap();
+    let summary = process_files(&[(file_path, 0)], &options, 0, &RunOptions::default()).unwrap();
+    assert_eq!(
+        summary.pattern_hits,
+        [("foo".to_string(), 1), ("foobar".to_string(), 1)]
+    );
+}
+
+#[test]
+fn test_unused_patterns_are_reported() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["DEBUG a", "INFO b", "WARN c"]);
+    let files = vec![(file_path, 0)];
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "DEBUG",
+        "DEBGU",
+        "WARN",
+        "EROR",
+        "--warn-unused-patterns",
+    ]);
+    let summary = process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        0,
+        &RunOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(cli::unused_patterns(&summary), ["DEBGU", "EROR"]);
+
+    // Nothing to warn about when one pattern only ever turns up inside another
+    let file_path = dir.path().join("overlap.gz");
+    write_gz_lines(&file_path, &["ERROR disk full", "INFO b"]);
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "ERR",
+        "ERROR",
+        "--warn-unused-patterns",
+    ]);
+    let summary = process_files(
+        &[(file_path, 0)],
+        &args.sieve_options(dir.path()).unwrap(),
+        0,
+        &RunOptions::default(),
+    )
+    .unwrap();
+    assert!(cli::unused_patterns(&summary).is_empty());
+}
+
+#[test]
+fn test_single_file_root() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    write_gz_lines(&file_path, &["keep", "DEBUG drop"]);
+    // Neighbours aren't picked up, and an unusual extension doesn't matter
+    write_gz_lines(&dir.path().join("other.gz"), &["DEBUG drop"]);
+    let odd_name = dir.path().join("rotated.1");
+    write_gz_lines(&odd_name, &["DEBUG drop"]);
+
+    let args = cli::parse_args_from(vec!["sieve", &file_path.to_string_lossy(), "DEBUG"]);
+    let (files, total_size) =
+        gather_gz_files(&args.roots(), &args.gather_options().unwrap()).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, file_path);
+    assert_eq!(total_size, std::fs::metadata(&file_path).unwrap().len());
+
+    let summary = process_files(
+        &files,
+        &args.sieve_options(cli::mirror_root(&file_path)).unwrap(),
+        total_size,
+        &RunOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(summary.total_lines_removed, 1);
+
+    let (files, _) = gather_gz_files(&[&odd_name], &GatherOptions::default()).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(cli::mirror_root(&odd_name), dir.path());
+    assert_eq!(cli::mirror_root(dir.path()), dir.path());
+}
+
+#[test]
+fn test_progress_bar_is_hidden_without_a_terminal() {
+    let args = Args::try_parse_from(["sieve", "/logs", "x"]).unwrap();
+    assert!(cli::progress_bar(&args, false).is_hidden());
+
+    let forced = Args::try_parse_from(["sieve", "--progress", "/logs", "x"]).unwrap();
+    assert!(!cli::progress_bar(&forced, false).is_hidden());
+
+    let quiet = Args::try_parse_from(["sieve", "--quiet", "/logs", "x"]).unwrap();
+    assert!(cli::progress_bar(&quiet, true).is_hidden());
+}
+
+#[test]
+fn test_declining_the_interactive_prompt() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["keep", "DEBUG drop"]);
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "DEBUG",
+        "--interactive",
+    ]);
+    let (files, total_size) =
+        gather_gz_files(&args.roots(), &args.gather_options().unwrap()).unwrap();
+    let mut prompt = Vec::new();
+    let proceed = cli::confirm(
+        &mut "n\n".as_bytes(),
+        &mut prompt,
+        files.len(),
+        total_size,
+        true,
+        args.yes,
+    )
+    .unwrap();
+    assert!(!proceed);
+    assert!(
+        String::from_utf8(prompt)
+            .unwrap()
+            .starts_with("About to process 1 files (")
+    );
+
+    assert!(cli::confirm(&mut "Yes\n".as_bytes(), &mut Vec::new(), 1, 0, true, false).unwrap());
+    // Without a terminal nothing is read, and --yes decides
+    assert!(!cli::confirm(&mut "y\n".as_bytes(), &mut Vec::new(), 1, 0, false, false).unwrap());
+    assert!(cli::confirm(&mut "".as_bytes(), &mut Vec::new(), 1, 0, false, true).unwrap());
+}
+
+#[test]
+fn test_bytes_saved_are_reported() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    let lines: Vec<String> = (0..5_000)
+        .map(|i| format!("{} request {i}", if i % 2 == 0 { "DEBUG" } else { "INFO" }))
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    write_gz_lines(&file_path, &lines);
+    let size = std::fs::metadata(&file_path).unwrap().len();
+
+    let summary = process_files(
+        &[(file_path.clone(), size)],
+        &SieveOptions::builder(["DEBUG"]).build().unwrap(),
+        size,
+        &RunOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(summary.total_bytes_before, size);
+    assert_eq!(
+        summary.total_bytes_after,
+        std::fs::metadata(&file_path).unwrap().len()
+    );
+    assert!(summary.total_bytes_after < summary.total_bytes_before);
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    let saved = summary.total_bytes_before - summary.total_bytes_after;
+    assert!(String::from_utf8(out).unwrap().contains(&format!(
+        "Files went from {} bytes to {} bytes, saving {} bytes.",
+        summary.total_bytes_before.to_formatted_string(&Locale::en),
+        summary.total_bytes_after.to_formatted_string(&Locale::en),
+        saved.to_formatted_string(&Locale::en),
+    )));
+}
+
+#[test]
+fn test_samples_of_removed_lines() {
+    let dir = tempdir().unwrap();
+    let mut files = Vec::new();
+    for name in ["a.gz", "b.gz"] {
+        let file_path = dir.path().join(name);
+        let lines: Vec<String> = (0..50)
+            .map(|i| format!("{} {name} {i}", if i % 2 == 0 { "DEBUG" } else { "INFO" }))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        write_gz_lines(&file_path, &lines);
+        files.push((file_path, 0));
+    }
+
+    let samples = Arc::new(cli::Samples::new(5));
+    let recorder = Arc::clone(&samples);
+    let options = SieveOptions::builder(["DEBUG"])
+        .on_removed(move |line| recorder.record(line))
+        .build()
+        .unwrap();
+    let run_options = RunOptions {
+        threads: Some(2),
+        ..Default::default()
+    };
+    let summary = process_files(&files, &options, 0, &run_options).unwrap();
+    assert_eq!(summary.total_lines_removed, 50);
+
+    let lines = samples.lines();
+    assert_eq!(lines.len(), 5);
+    assert!(lines.iter().all(|line| line.starts_with("DEBUG ")));
+
+    let mut out = Vec::new();
+    cli::print_samples(&mut out, &lines).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("Sample of removed lines:\n  DEBUG "));
+    assert_eq!(out.lines().count(), 6);
+}
+
+#[test]
+fn test_largest_files_are_processed_first() {
+    let files = vec![
+        (PathBuf::from("small.gz"), 10),
+        (PathBuf::from("huge.gz"), 10_000),
+        (PathBuf::from("medium.gz"), 500),
+    ];
+    let ordered: Vec<_> = largest_first(&files)
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect();
+    assert_eq!(
+        ordered,
+        ["huge.gz", "medium.gz", "small.gz"].map(PathBuf::from)
+    );
+
+    // The order doesn't change what gets filtered
+    let dir = tempdir().unwrap();
+    let mut files = Vec::new();
+    for (name, count) in [("small.gz", 2), ("huge.gz", 2000), ("medium.gz", 100)] {
+        let file_path = dir.path().join(name);
+        let lines: Vec<String> = (0..count).map(|i| format!("line {i}")).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        write_gz_lines(&file_path, &lines);
+        files.push((
+            file_path.clone(),
+            std::fs::metadata(&file_path).unwrap().len(),
+        ));
+    }
+    let summary = process_files(
+        &files,
+        &SieveOptions::builder(["line 1"]).build().unwrap(),
+        0,
+        &RunOptions {
+            threads: Some(2),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    // "line 1" also matches line 10-19, 100-199 and 1000-1999 where present
+    assert_eq!(summary.total_lines_read, 2102);
+    assert_eq!(summary.total_lines_removed, 1 + 11 + 1111);
+    assert_eq!(summary.files_processed, 3);
+}
+
+#[test]
+fn test_process_files() {
+    // Create a test directory with some files
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+
+    // Create a gzipped file with some content
+    {
+        let file = File::create(&file_path).unwrap();
+        let gz = GzEncoder::new(file, Compression::default());
+        let mut writer = BufWriter::new(gz);
+        writeln!(writer, "line 1").unwrap();
+        writeln!(writer, "line 2 pattern").unwrap();
+        writeln!(writer, "line 3").unwrap();
+    }
+
+    // Get file size for progress tracking
     let size = std::fs::metadata(&file_path).unwrap().len();
     let files = vec![(file_path.clone(), size)];
 
     // Test process_files with patterns
     let patterns = vec!["pattern".to_string()];
-    let result = super::process_files(&files, &patterns, &Mode::Remove, size, Some(1));
+    let result = super::process_files(
+        &files,
+        &SieveOptions::builder(&patterns).build().unwrap(),
+        size,
+        &RunOptions {
+            threads: Some(1),
+            ..Default::default()
+        },
+    );
 
     assert!(result.is_ok());
-    let (read, removed) = result.unwrap();
-    assert_eq!(read, 3);
-    assert_eq!(removed, 1);
+    let summary = result.unwrap();
+    assert_eq!(summary.total_lines_read, 3);
+    assert_eq!(summary.total_lines_removed, 1);
+    assert_eq!(summary.files_processed, 1);
+    assert_eq!(summary.files_failed, 0);
 
     // Verify file contents were modified
     let file = File::open(&file_path).unwrap();
@@ -593,28 +1737,27 @@ fn test_main_workflow() {
     }
 
     // Parse test arguments
-    let args = super::parse_args_from(vec!["sieve", &dir.path().to_string_lossy(), "REMOVE"]);
+    let args = cli::parse_args_from(vec!["sieve", &dir.path().to_string_lossy(), "REMOVE"]);
 
     // Skip logging setup for test (would interfere with test harness logging)
-    // let log_file = super::setup_logging(&args.log_output).unwrap();
+    // let log_file = cli::setup_logging(&args.log_output).unwrap();
 
     // Process the root directory to find gz files
-    let root = Path::new(&args.root_dir);
-    let (gz_files, total_size) = super::gather_gz_files(root);
+    let (gz_files, total_size) =
+        super::gather_gz_files(&args.roots(), &args.gather_options().unwrap()).unwrap();
 
     // Process files
-    let (total_lines_read, total_lines_removed) = super::process_files(
+    let summary = super::process_files(
         &gz_files,
-        &args.patterns,
-        &Mode::Remove,
+        &args.sieve_options(&args.roots()[0]).unwrap(),
         total_size,
-        args.threads,
+        &args.run_options().unwrap(),
     )
     .unwrap();
 
     // Check results
-    assert_eq!(total_lines_read, 10);
-    assert_eq!(total_lines_removed, 5); // Every other line should be removed
+    assert_eq!(summary.total_lines_read, 10);
+    assert_eq!(summary.total_lines_removed, 5); // Every other line should be removed
 
     // Verify file was modified correctly
     let file = File::open(&file_path).unwrap();
@@ -628,3 +1771,3078 @@ fn test_main_workflow() {
         assert!(!line.contains("REMOVE"));
     }
 }
+
+#[test]
+fn test_regex_patterns() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(
+        &file_path,
+        &[
+            "2024-01-01 started",
+            "client 10.0.0.1 connected",
+            "plain line",
+        ],
+    );
+
+    let patterns = vec![
+        r"^\d{4}-\d{2}-\d{2}".to_string(),
+        r"\d+\.\d+\.\d+\.\d+".to_string(),
+    ];
+    let matcher = Matcher::new(&patterns, MatchKind::Regex).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 2);
+    assert_eq!(read_gz_lines(&file_path), vec!["plain line"]);
+}
+
+#[test]
+fn test_regex_disabled_matches_literally() {
+    let patterns = vec![r"^\d+".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+
+    assert!(!matcher.is_match("123 numbers"));
+    assert!(matcher.is_match(r"literal ^\d+ text"));
+}
+
+#[test]
+fn test_invalid_regex_pattern() {
+    let patterns = vec!["valid".to_string(), "(unclosed".to_string()];
+    let result = Matcher::new(&patterns, MatchKind::Regex);
+
+    match result {
+        Err(SieveError::InvalidPattern { pattern, .. }) => assert_eq!(pattern, "(unclosed"),
+        other => panic!("Expected InvalidPattern error, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_starts_with_ignores_mid_line_matches() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(
+        &file_path,
+        &["DEBUG starting", "INFO DEBUG in the middle", "INFO done"],
+    );
+
+    let patterns = vec!["DEBUG".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::StartsWith).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+    assert_eq!(
+        read_gz_lines(&file_path),
+        vec!["INFO DEBUG in the middle", "INFO done"]
+    );
+}
+
+#[test]
+fn test_ends_with_ignores_mid_line_matches() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(
+        &file_path,
+        &["request healthcheck", "healthcheck request", "other"],
+    );
+
+    let patterns = vec!["healthcheck".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::EndsWith).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+    assert_eq!(
+        read_gz_lines(&file_path),
+        vec!["healthcheck request", "other"]
+    );
+}
+
+#[test]
+fn test_anchored_flags_are_mutually_exclusive() {
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "x", "--starts-with"]);
+    assert_eq!(args.match_kind(), MatchKind::StartsWith);
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "x", "--ends-with"]);
+    assert_eq!(args.match_kind(), MatchKind::EndsWith);
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "x", "--exact"]);
+    assert_eq!(args.match_kind(), MatchKind::Exact);
+
+    let result = Args::try_parse_from(vec!["sieve", "/tmp", "x", "--starts-with", "--ends-with"]);
+    assert!(result.is_err());
+
+    let result = Args::try_parse_from(vec!["sieve", "/tmp", "x", "--exact", "--regex"]);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_exact_match_mode() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "pattern"]);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Exact).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1", "line 2 pattern"]);
+}
+
+#[test]
+fn test_contains_mode_removes_partial_match() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "pattern"]);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 2);
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1"]);
+}
+
+#[test]
+fn test_contains_matcher_agrees_with_naive_substring_search() {
+    let patterns: Vec<String> = (0..1000).map(|i| format!("token{i}-")).collect();
+    let lines: Vec<String> = (0..5000)
+        .map(|i| format!("request {i} token{}- status ok", i * 7 % 3000))
+        .collect();
+
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let matched = lines.iter().filter(|l| matcher.is_match(l)).count();
+    let expected = lines
+        .iter()
+        .filter(|l| patterns.iter().any(|pat| l.contains(pat)))
+        .count();
+    assert_eq!(matched, expected);
+    assert!(matched > 0 && matched < lines.len());
+}
+
+#[test]
+fn test_dry_run_leaves_file_untouched() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "line 3 pattern"]);
+    let original = std::fs::read(&file_path).unwrap();
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let options = SieveOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let FileResult { read, removed, .. } = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 2);
+    assert_eq!(std::fs::read(&file_path).unwrap(), original);
+}
+
+#[test]
+fn test_backup_keeps_original_content() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "line 3"]);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let options = SieveOptions {
+        backup_suffix: Some(".bak".to_string()),
+        ..Default::default()
+    };
+    let FileResult { read, removed, .. } = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1", "line 3"]);
+    assert_eq!(
+        read_gz_lines(&dir.path().join("test.gz.bak")),
+        vec!["line 1", "line 2 pattern", "line 3"]
+    );
+}
+
+#[test]
+fn test_backup_does_not_clobber_existing_backup() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    let backup_path = dir.path().join("test.gz.bak");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern"]);
+    write_gz_lines(&backup_path, &["older backup"]);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let mut options = SieveOptions {
+        backup_suffix: Some(".bak".to_string()),
+        ..Default::default()
+    };
+
+    // By default an existing backup fails the file and leaves everything alone
+    let result = filter_lines(&file_path, &matcher, &options);
+    assert!(matches!(result, Err(SieveError::BackupExists(_))));
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1", "line 2 pattern"]);
+
+    // Keeping the existing backup lets the file be filtered
+    options.keep_existing_backup = true;
+    filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1"]);
+    assert_eq!(read_gz_lines(&backup_path), vec!["older backup"]);
+}
+
+#[test]
+fn test_parse_backup_suffix() {
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "--backup", "pattern"]);
+    assert_eq!(args.backup.as_deref(), Some(".bak"));
+    assert_eq!(args.patterns, vec!["pattern"]);
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "--backup=.orig", "pattern"]);
+    assert_eq!(args.backup.as_deref(), Some(".orig"));
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "pattern"]);
+    assert_eq!(args.backup, None);
+}
+
+#[test]
+fn test_output_dir_mirrors_source_tree() {
+    let source = tempdir().unwrap();
+    let output = tempdir().unwrap();
+    let nested_dir = source.path().join("2024").join("01");
+    std::fs::create_dir_all(&nested_dir).unwrap();
+    let file_path = nested_dir.join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "line 3"]);
+    let original = std::fs::read(&file_path).unwrap();
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &source.path().to_string_lossy(),
+        "pattern",
+        "--output-dir",
+        &output.path().to_string_lossy(),
+    ]);
+    let matcher = Matcher::new(&args.patterns, args.match_kind()).unwrap();
+    let options = args.sieve_options(source.path()).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+    assert_eq!(std::fs::read(&file_path).unwrap(), original);
+    assert_eq!(
+        read_gz_lines(&output.path().join("2024").join("01").join("test.gz")),
+        vec!["line 1", "line 3"]
+    );
+}
+
+#[test]
+fn test_removed_output_records_removed_lines() {
+    let dir = tempdir().unwrap();
+    let first = dir.path().join("first.gz");
+    let second = dir.path().join("second.gz");
+    write_gz_lines(&first, &["keep a", "drop a1", "drop a2"]);
+    write_gz_lines(&second, &["drop b1", "keep b"]);
+    let sidecar = dir.path().join("removed.txt");
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--removed-output",
+        &sidecar.to_string_lossy(),
+    ]);
+    let files = vec![(first.clone(), 0), (second.clone(), 0)];
+    let options = args.sieve_options(dir.path()).unwrap();
+    let summary = super::process_files(&files, &options, 0, &args.run_options().unwrap()).unwrap();
+
+    assert_eq!(summary.total_lines_read, 5);
+    assert_eq!(summary.total_lines_removed, 3);
+
+    let mut recorded: Vec<String> = std::fs::read_to_string(&sidecar)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    recorded.sort();
+    assert_eq!(
+        recorded,
+        vec![
+            format!("{}\tdrop a1", first.display()),
+            format!("{}\tdrop a2", first.display()),
+            format!("{}\tdrop b1", second.display()),
+        ]
+    );
+}
+
+#[test]
+fn test_dry_run_diff() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    write_gz_lines(
+        &file_path,
+        &["keep 1", "drop 2", "drop 3", "keep 4", "drop 5", "keep 6"],
+    );
+    let original = std::fs::read(&file_path).unwrap();
+
+    let buffer = SharedBuffer::default();
+    let options = SieveOptions::builder(["drop"])
+        .dry_run(true)
+        .diff_output(buffer.clone())
+        .build()
+        .unwrap();
+    let files = vec![(file_path.clone(), 0)];
+    let run_options = RunOptions::default();
+    let summary = process_files(&files, &options, 0, &run_options).unwrap();
+
+    assert_eq!(summary.total_lines_removed, 3);
+    assert_eq!(std::fs::read(&file_path).unwrap(), original);
+    let diff = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let path = file_path.display();
+    assert_eq!(
+        diff,
+        format!(
+            "--- {path}\n+++ {path}\n@@ -2,2 +1,0 @@\n-drop 2\n-drop 3\n@@ -5,1 +2,0 @@\n-drop 5\n"
+        )
+    );
+}
+
+#[test]
+fn test_diff_requires_dry_run() {
+    assert!(Args::try_parse_from(["sieve", "logs", "DEBUG", "--diff"]).is_err());
+    let args = Args::try_parse_from(["sieve", "logs", "DEBUG", "--dry-run", "--diff"]).unwrap();
+    assert!(args.diff);
+}
+
+#[test]
+fn test_concatenated_gzip_members() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("appended.gz");
+    let mut contents = Vec::new();
+    for member in ["first keep\nfirst drop\n", "second drop\nsecond keep\n"] {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(member.as_bytes()).unwrap();
+        contents.extend(gz.finish().unwrap());
+    }
+    std::fs::write(&file_path, contents).unwrap();
+
+    let options = SieveOptions::builder(["drop"]).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    assert_eq!(result.read, 4);
+    assert_eq!(result.removed, 2);
+    // Written back as a single member, so a plain decoder sees every line
+    assert_eq!(read_gz_lines(&file_path), vec!["first keep", "second keep"]);
+}
+
+#[test]
+fn test_gzip_output_is_reproducible() {
+    let dir = tempdir().unwrap();
+    let first = dir.path().join("first.gz");
+    let second = dir.path().join("second.gz");
+    write_gz_lines(&first, &["keep", "drop", "keep again"]);
+    write_gz_lines(&second, &["keep", "drop", "keep again"]);
+    // Inputs from different times, so nothing time-based can leak into the output
+    filetime::set_file_mtime(&first, filetime::FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+    filetime::set_file_mtime(
+        &second,
+        filetime::FileTime::from_unix_time(1_700_000_000, 0),
+    )
+    .unwrap();
+
+    let options = SieveOptions::builder(["drop"]).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    filter_lines(&first, &matcher, &options).unwrap();
+    filter_lines(&second, &matcher, &options).unwrap();
+
+    let first = std::fs::read(&first).unwrap();
+    assert_eq!(first, std::fs::read(&second).unwrap());
+    // mtime field of the header, with no file name flag set
+    assert_eq!(first[4..8], [0, 0, 0, 0]);
+    assert_eq!(first[3] & 0x08, 0);
+}
+
+#[test]
+fn test_verify_output() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    write_gz_lines(&file_path, &["keep", "drop", "keep too"]);
+
+    let options = SieveOptions::builder(["drop"])
+        .verify(true)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!(result.removed, 1);
+    assert_eq!(read_gz_lines(&file_path), vec!["keep", "keep too"]);
+
+    // A truncated stream is caught before it could replace anything
+    let truncated = dir.path().join("truncated.gz");
+    let contents = std::fs::read(&file_path).unwrap();
+    std::fs::write(&truncated, &contents[..contents.len() - 4]).unwrap();
+    let err = verify_output(&truncated, Codec::Gzip, &file_path).unwrap_err();
+    assert!(matches!(err, SieveError::Processing(msg) if msg.contains("failed verification")));
+}
+
+#[test]
+fn test_match_all_requires_every_pattern() {
+    let lines = [
+        "ERROR request timeout",
+        "ERROR disk full",
+        "WARN timeout",
+        "INFO ok",
+    ];
+    let sieve = |options: SieveOptions| {
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let mut out = Vec::new();
+        sieve_stream(
+            lines.join("\n").as_bytes(),
+            &mut out,
+            "test",
+            &matcher,
+            &options,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let any = SieveOptions::builder(["ERROR", "timeout"]).build().unwrap();
+    assert_eq!(sieve(any), "INFO ok");
+
+    let all = SieveOptions::builder(["ERROR", "timeout"])
+        .match_all(true)
+        .build()
+        .unwrap();
+    assert_eq!(sieve(all), "ERROR disk full\nWARN timeout\nINFO ok");
+
+    let regex = SieveOptions::builder([r"^ERROR", r"time\w+$"])
+        .match_kind(MatchKind::Regex)
+        .match_all(true)
+        .build()
+        .unwrap();
+    assert_eq!(sieve(regex), "ERROR disk full\nWARN timeout\nINFO ok");
+}
+
+#[test]
+fn test_match_all_with_overlapping_patterns() {
+    let matcher = Matcher::new(
+        &["ERR".to_string(), "ERROR".to_string()],
+        MatchKind::Contains,
+    )
+    .unwrap();
+    assert!(matcher.is_match_all("ERROR here"));
+    assert!(!matcher.is_match_all("ERR only"));
+    assert!(
+        !Matcher::new(&[], MatchKind::Contains)
+            .unwrap()
+            .is_match_all("anything")
+    );
+}
+
+#[test]
+fn test_keep_patterns_protect_lines() {
+    let lines = [
+        "DEBUG routine",
+        "DEBUG IMPORTANT state",
+        "INFO IMPORTANT",
+        "INFO ok",
+    ];
+    let sieve = |options: SieveOptions| {
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let mut out = Vec::new();
+        let (_, removed) = sieve_stream(
+            lines.join("\n").as_bytes(),
+            &mut out,
+            "test",
+            &matcher,
+            &options,
+        )
+        .unwrap();
+        (String::from_utf8(out).unwrap(), removed)
+    };
+
+    let options = SieveOptions::builder(["DEBUG"])
+        .keep_patterns(["IMPORTANT"])
+        .build()
+        .unwrap();
+    assert_eq!(
+        sieve(options),
+        (
+            "DEBUG IMPORTANT state\nINFO IMPORTANT\nINFO ok".to_string(),
+            1
+        )
+    );
+
+    // In keep mode the carve-out saves lines that match no pattern
+    let options = SieveOptions::builder(["INFO"])
+        .mode(Mode::Keep)
+        .keep_patterns(["IMPORTANT"])
+        .build()
+        .unwrap();
+    assert_eq!(
+        sieve(options).0,
+        "DEBUG IMPORTANT state\nINFO IMPORTANT\nINFO ok"
+    );
+}
+
+#[test]
+fn test_keep_patterns_share_match_kind() {
+    let options = SieveOptions::builder([r"^DEBUG"])
+        .match_kind(MatchKind::Regex)
+        .keep_patterns([r"state=\d+"])
+        .build()
+        .unwrap();
+    let keep = options.keep_matcher.as_ref().unwrap();
+    assert!(keep.is_match("DEBUG state=42"));
+    assert!(!keep.is_match("DEBUG state=x"));
+
+    let result = SieveOptions::builder(["x"])
+        .match_kind(MatchKind::Regex)
+        .keep_patterns(["("])
+        .build();
+    assert!(matches!(result, Err(SieveError::InvalidPattern { .. })));
+}
+
+#[test]
+fn test_match_on_field() {
+    let tsv = "2024-01-01\tweb\tGET /health\n\
+               2024-01-01\thealth\tGET /users\n\
+               short\tline\n\
+               2024-01-02\tweb\tPOST /health/check\n";
+    let sieve = |options: SieveOptions| {
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let mut out = Vec::new();
+        sieve_stream(tsv.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let third = Field {
+        number: 3,
+        separator: Delimiter(b'\t'),
+    };
+
+    // Only the third column is checked, so "health" in the second one doesn't count
+    let options = SieveOptions::builder(["health"])
+        .field(third)
+        .build()
+        .unwrap();
+    assert_eq!(
+        sieve(options),
+        "2024-01-01\thealth\tGET /users\nshort\tline\n"
+    );
+
+    let options = SieveOptions::builder(["GET"])
+        .match_kind(MatchKind::StartsWith)
+        .field(third)
+        .drop_missing_field(true)
+        .build()
+        .unwrap();
+    assert_eq!(sieve(options), "2024-01-02\tweb\tPOST /health/check\n");
+
+    // Keep patterns look at the same column, so "web" elsewhere protects nothing
+    let options = SieveOptions::builder(["health"])
+        .field(third)
+        .keep_patterns(["check", "web"])
+        .build()
+        .unwrap();
+    assert_eq!(
+        sieve(options),
+        "2024-01-01\thealth\tGET /users\n\
+         short\tline\n\
+         2024-01-02\tweb\tPOST /health/check\n"
+    );
+}
+
+#[test]
+fn test_redact_within_field() {
+    let options = SieveOptions::builder(["secret"])
+        .mode(Mode::Redact)
+        .replacement("***")
+        .field(Field {
+            number: 2,
+            separator: Delimiter(b','),
+        })
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let mut out = Vec::new();
+    let input = "secret,a secret,secret\n";
+    sieve_stream(input.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "secret,a ***,secret\n");
+
+    let zero = Field {
+        number: 0,
+        separator: Delimiter(b','),
+    };
+    let result = SieveOptions::builder(["x"]).field(zero).build();
+    assert!(matches!(result, Err(SieveError::InvalidOptions(_))));
+}
+
+#[test]
+fn test_keep_header() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("report.csv.gz");
+    write_gz_lines(
+        &file_path,
+        &["id,status", "1,ok", "2,status unknown", "3,ok"],
+    );
+
+    let options = SieveOptions::builder(["status"])
+        .keep_header(1)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    assert_eq!(result.read, 4);
+    assert_eq!(result.removed, 1);
+    assert_eq!(read_gz_lines(&file_path), vec!["id,status", "1,ok", "3,ok"]);
+}
+
+#[test]
+fn test_skip_lines() {
+    let input = "# DEBUG preamble\n# generated\nid DEBUG\nDEBUG 1\nINFO 2\n";
+    let options = SieveOptions::builder(["DEBUG"])
+        .skip_lines(2)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let mut out = Vec::new();
+    let counts = sieve_stream(input.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+    assert_eq!(counts, (3, 2));
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "# DEBUG preamble\n# generated\nINFO 2\n"
+    );
+
+    // The header is the first line after the skipped ones
+    let options = SieveOptions::builder(["DEBUG"])
+        .skip_lines(2)
+        .keep_header(1)
+        .build()
+        .unwrap();
+    let mut out = Vec::new();
+    let counts = sieve_stream(input.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+    assert_eq!(counts, (3, 1));
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "# DEBUG preamble\n# generated\nid DEBUG\nINFO 2\n"
+    );
+}
+
+#[test]
+fn test_keep_header_in_parallel() {
+    let lines: Vec<String> = ["# preamble".to_string(), "DEBUG header".to_string()]
+        .into_iter()
+        .chain((0..50_000).map(|i| format!("{} {i}", if i % 3 == 0 { "DEBUG" } else { "INFO" })))
+        .collect();
+    let input = lines.join("\n") + "\n";
+    let options = SieveOptions::builder(["DEBUG"])
+        .skip_lines(1)
+        .keep_header(1)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+
+    let mut sequential = Vec::new();
+    let counts = sieve_stream(
+        input.as_bytes(),
+        &mut sequential,
+        "test",
+        &matcher,
+        &options,
+    )
+    .unwrap();
+    let mut parallel = Vec::new();
+    let parallel_counts = sieve_stream_parallel(
+        input.as_bytes(),
+        &mut parallel,
+        "test",
+        &matcher,
+        &options,
+        4,
+    )
+    .unwrap();
+
+    assert_eq!(counts, parallel_counts);
+    assert_eq!(sequential, parallel);
+    assert!(sequential.starts_with(b"# preamble\nDEBUG header\nINFO 1\n"));
+}
+
+#[test]
+fn test_max_throughput() {
+    let line = format!("{}\n", "x".repeat(99));
+    let input = line.repeat(3_000);
+    let options = SieveOptions::builder(["DEBUG"])
+        .max_throughput(Some(200_000))
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+
+    let start = Instant::now();
+    let mut out = Vec::new();
+    sieve_stream(input.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+    // 300,000 bytes at 200,000 per second, less the first batch that goes straight through
+    let floor = Duration::from_secs_f64((input.len() - THROTTLE_BATCH) as f64 / 200_000.0);
+    assert!(start.elapsed() >= floor, "took {:?}", start.elapsed());
+    assert_eq!(out, input.as_bytes());
+
+    let result = SieveOptions::builder(["x"]).max_throughput(Some(0)).build();
+    assert!(matches!(result, Err(SieveError::InvalidOptions(_))));
+}
+
+#[test]
+fn test_tar_members_are_filtered() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("logs.tar.gz");
+    let mut builder = tar::Builder::new(GzEncoder::new(
+        File::create(&file_path).unwrap(),
+        Compression::default(),
+    ));
+    let mut append = |path: &str, entry_type: tar::EntryType, mode: u32, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_mtime(1_700_000_000);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, data).unwrap();
+    };
+    append("app/", tar::EntryType::Directory, 0o755, b"");
+    append(
+        "app/web.log",
+        tar::EntryType::Regular,
+        0o640,
+        b"INFO a\nDEBUG b\nINFO c\n",
+    );
+    append(
+        "app/db.log",
+        tar::EntryType::Regular,
+        0o600,
+        b"DEBUG x\nWARN y\n",
+    );
+    append(
+        "app/blob.bin",
+        tar::EntryType::Regular,
+        0o644,
+        b"DEBUG\0\x01\n",
+    );
+    builder.into_inner().unwrap().finish().unwrap();
+
+    let options = SieveOptions::builder(["DEBUG"]).tar(true).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!(result.read, 5);
+    assert_eq!(result.removed, 2);
+
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(&file_path).unwrap()));
+    let entries: Vec<(String, u32, u64, Vec<u8>)> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            let header = entry.header();
+            (
+                entry.path().unwrap().display().to_string(),
+                header.mode().unwrap(),
+                header.mtime().unwrap(),
+                data,
+            )
+        })
+        .collect();
+    assert_eq!(
+        entries,
+        vec![
+            ("app/".to_string(), 0o755, 1_700_000_000, b"".to_vec()),
+            (
+                "app/web.log".to_string(),
+                0o640,
+                1_700_000_000,
+                b"INFO a\nINFO c\n".to_vec()
+            ),
+            (
+                "app/db.log".to_string(),
+                0o600,
+                1_700_000_000,
+                b"WARN y\n".to_vec()
+            ),
+            (
+                "app/blob.bin".to_string(),
+                0o644,
+                1_700_000_000,
+                b"DEBUG\0\x01\n".to_vec()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_count_prints_per_file_matches() {
+    let dir = tempdir().unwrap();
+    write_gz_lines(&dir.path().join("a.gz"), &["DEBUG 1", "INFO", "DEBUG 2"]);
+    write_gz_lines(&dir.path().join("b.gz"), &["INFO"]);
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    write_gz_lines(&dir.path().join("sub/c.gz"), &["DEBUG", "DEBUG", "DEBUG"]);
+    let before = std::fs::read(dir.path().join("a.gz")).unwrap();
+
+    let root = dir.path().to_string_lossy();
+    let args = cli::parse_args_from(vec!["sieve", &root, "DEBUG", "-c"]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let summary = process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    let mut out = Vec::new();
+    cli::report_summary(&mut out, &args, &summary).unwrap();
+
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!(
+            "{}:2\n{}:0\n{}:3\n",
+            path("a.gz"),
+            path("b.gz"),
+            path("sub/c.gz")
+        )
+    );
+    assert_eq!(std::fs::read(dir.path().join("a.gz")).unwrap(), before);
+}
+
+#[test]
+fn test_metrics_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sieve.prom");
+    let summary = RunSummary {
+        total_lines_read: 1_500,
+        total_lines_removed: 42,
+        files_processed: 3,
+        files_skipped: 2,
+        files_failed: 1,
+        elapsed_seconds: 2.5,
+        ..Default::default()
+    };
+    cli::write_metrics(&path, &summary).unwrap();
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    let samples: Vec<(&str, f64)> = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let (name, value) = line.split_once(' ').unwrap();
+            (name, value.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(
+        samples,
+        [
+            ("sieve_lines_read_total", 1_500.0),
+            ("sieve_lines_removed_total", 42.0),
+            ("sieve_files_processed_total", 3.0),
+            ("sieve_files_skipped_total", 2.0),
+            ("sieve_files_failed_total", 1.0),
+            ("sieve_duration_seconds", 2.5),
+        ]
+    );
+    assert!(text.contains("# TYPE sieve_lines_read_total counter\n"));
+    assert!(text.contains("# TYPE sieve_duration_seconds gauge\n"));
+}
+
+#[test]
+fn test_sequential_matches_parallel() {
+    let dir = tempdir().unwrap();
+    let mut files = Vec::new();
+    for name in ["c.gz", "a.gz", "b.gz", "d.gz"] {
+        let file_path = dir.path().join(name);
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!("{} {name} {i}", if i % 4 == 0 { "DEBUG" } else { "INFO" }))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        write_gz_lines(&file_path, &lines);
+        files.push((file_path, 0));
+    }
+    let copies = tempdir().unwrap();
+    let mut copied = Vec::new();
+    for (path, size) in &files {
+        let copy = copies.path().join(path.file_name().unwrap());
+        std::fs::copy(path, &copy).unwrap();
+        copied.push((copy, *size));
+    }
+
+    let options = SieveOptions::builder(["DEBUG"]).build().unwrap();
+    let run = |files: &[(PathBuf, u64)], sequential: bool| {
+        let run_options = RunOptions {
+            threads: Some(4),
+            sequential,
+            file_results: true,
+            ..Default::default()
+        };
+        process_files(files, &options, 0, &run_options).unwrap()
+    };
+    let parallel = run(&files, false);
+    let sequential = run(&copied, true);
+
+    assert_eq!(sequential.total_lines_read, parallel.total_lines_read);
+    assert_eq!(sequential.total_lines_removed, parallel.total_lines_removed);
+    assert_eq!(sequential.files_processed, 4);
+    let results = |summary: &RunSummary| -> Vec<(std::ffi::OsString, u64, u64)> {
+        summary
+            .file_results
+            .iter()
+            .map(|(path, result)| {
+                (
+                    path.file_name().unwrap().to_owned(),
+                    result.read,
+                    result.removed,
+                )
+            })
+            .collect()
+    };
+    assert_eq!(results(&sequential), results(&parallel));
+    for (path, _) in &files {
+        let copy = copies.path().join(path.file_name().unwrap());
+        assert_eq!(read_gz_lines(path), read_gz_lines(&copy));
+    }
+}
+
+#[test]
+fn test_thread_count_must_be_positive() {
+    let err = Args::try_parse_from(["sieve", "/tmp", "x", "--threads", "0"]).unwrap_err();
+    assert!(err.to_string().contains("must be at least 1"));
+    let args = Args::try_parse_from(["sieve", "/tmp", "x", "--threads", "4"]).unwrap();
+    assert_eq!(args.threads, Some(4));
+
+    // The library checks as well, rather than leaving 0 to the thread pool
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("a.gz");
+    write_gz_lines(&file_path, &["x", "y"]);
+    let options = SieveOptions::builder(["x"]).build().unwrap();
+    let run_options = RunOptions {
+        threads: Some(0),
+        ..Default::default()
+    };
+    let result = process_files(&[(file_path.clone(), 0)], &options, 0, &run_options);
+    assert!(matches!(result, Err(SieveError::InvalidOptions(_))));
+    assert_eq!(read_gz_lines(&file_path), vec!["x", "y"]);
+}
+
+#[test]
+fn test_output_dir_places_urls_by_host_and_path() {
+    let output_dir = OutputDir {
+        root: PathBuf::from("/logs"),
+        dir: PathBuf::from("/filtered"),
+    };
+    let target = |url: &str| output_dir.target_for(Path::new(url));
+
+    assert_eq!(
+        target("https://example.com/logs/app.log.gz?sig=abc#top").unwrap(),
+        Path::new("/filtered/example.com/logs/app.log.gz")
+    );
+    assert_eq!(
+        target("http://localhost:8080/app.log.gz").unwrap(),
+        Path::new("/filtered/localhost_8080/app.log.gz")
+    );
+    assert!(target("https://example.com/").is_err());
+    assert!(target("https://example.com/logs/").is_err());
+    assert!(target("https://example.com/../etc/app.log.gz").is_err());
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_filter_lines_fetches_urls_into_output_dir() {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    let dir = tempdir().unwrap();
+    let served = dir.path().join("served.log.gz");
+    write_gz_lines(&served, &["keep 1", "DEBUG drop", "keep 2"]);
+    let body = std::fs::read(&served).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(&body).unwrap();
+        String::from_utf8(request).unwrap()
+    });
+
+    let url = format!("http://127.0.0.1:{port}/logs/app.log.gz");
+    let out = dir.path().join("out");
+    let options = SieveOptions::builder(["DEBUG"])
+        .output_dir(dir.path(), &out)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(Path::new(&url), &matcher, &options).unwrap();
+
+    assert!(
+        server
+            .join()
+            .unwrap()
+            .starts_with("GET /logs/app.log.gz HTTP/1.1\r\n")
+    );
+    assert_eq!((result.read, result.removed), (3, 1));
+    let target = out.join(format!("127.0.0.1_{port}/logs/app.log.gz"));
+    assert_eq!(read_gz_lines(&target), vec!["keep 1", "keep 2"]);
+    assert_eq!(
+        result.bytes_after,
+        std::fs::metadata(&target).unwrap().len()
+    );
+
+    let in_place = SieveOptions::builder(["DEBUG"]).build().unwrap();
+    assert!(matches!(
+        filter_lines(Path::new(&url), &matcher, &in_place),
+        Err(SieveError::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn test_cancel_stops_the_run_with_partial_counts() {
+    let dir = tempdir().unwrap();
+    for name in ["a.gz", "b.gz", "c.gz"] {
+        write_gz_lines(&dir.path().join(name), &["keep", "drop"]);
+    }
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+
+    // Cancelled while the first file is being read, which still finishes
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&cancel);
+    let options = SieveOptions::builder(["drop"])
+        .cancel(Arc::clone(&cancel))
+        .on_removed(move |_| flag.store(true, Ordering::Relaxed))
+        .build()
+        .unwrap();
+    let run_options = RunOptions {
+        sequential: true,
+        ..Default::default()
+    };
+    let summary = super::process_files(&files, &options, total_size, &run_options).unwrap();
+
+    assert_eq!(summary.files_processed, 1);
+    assert_eq!(summary.files_failed, 0);
+    assert_eq!(
+        (summary.total_lines_read, summary.total_lines_removed),
+        (2, 1)
+    );
+    assert_eq!(read_gz_lines(&dir.path().join("a.gz")), vec!["keep"]);
+    for name in ["b.gz", "c.gz"] {
+        assert_eq!(read_gz_lines(&dir.path().join(name)), vec!["keep", "drop"]);
+    }
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn test_cancel_abandons_a_file_part_way_through() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("big.gz");
+    let lines: Vec<String> = (0..10_000).map(|i| format!("drop {i}")).collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    write_gz_lines(&file_path, &lines);
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&cancel);
+    let options = SieveOptions::builder(["drop"])
+        .cancel(cancel)
+        .on_removed(move |_| flag.store(true, Ordering::Relaxed))
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+
+    let result = filter_lines(&file_path, &matcher, &options);
+    assert!(matches!(result, Err(SieveError::Cancelled)));
+    assert_eq!(read_gz_lines(&file_path).len(), 10_000);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_progress_sink_receives_every_file_size() {
+    #[derive(Default)]
+    struct Totals {
+        expected: AtomicU64,
+        reported: AtomicU64,
+        finished: AtomicBool,
+    }
+
+    impl ProgressSink for Totals {
+        fn on_start(&self, total_size: u64) {
+            self.expected.store(total_size, Ordering::Relaxed);
+        }
+
+        fn on_progress(&self, bytes: u64) {
+            self.reported.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        fn on_finish(&self, completed: bool) {
+            self.finished.store(completed, Ordering::Relaxed);
+        }
+    }
+
+    let dir = tempdir().unwrap();
+    for (i, name) in ["a.gz", "b.gz", "c.gz"].into_iter().enumerate() {
+        let lines: Vec<String> = (0..i * 100).map(|n| format!("line {n}")).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        write_gz_lines(&dir.path().join(name), &lines);
+    }
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+
+    let totals = Arc::new(Totals::default());
+    let run_options = RunOptions {
+        progress: Some(Arc::clone(&totals) as Arc<dyn ProgressSink>),
+        ..Default::default()
+    };
+    let options = SieveOptions::builder(["line 1"]).build().unwrap();
+    process_files(&files, &options, total_size, &run_options).unwrap();
+
+    assert_eq!(totals.expected.load(Ordering::Relaxed), total_size);
+    assert_eq!(totals.reported.load(Ordering::Relaxed), total_size);
+    assert!(totals.finished.load(Ordering::Relaxed));
+}
+
+#[test]
+fn test_removal_histogram() {
+    assert_eq!(
+        [0, 1, 10, 11, 100, 101, u64::MAX].map(histogram_bucket),
+        [0, 1, 1, 2, 2, 3, 20]
+    );
+
+    let dir = tempdir().unwrap();
+    for (name, dropped) in [
+        ("a", 0),
+        ("b", 0),
+        ("c", 5),
+        ("d", 10),
+        ("e", 11),
+        ("f", 150),
+    ] {
+        let mut lines = vec!["keep".to_string()];
+        lines.extend((0..dropped).map(|i| format!("drop {i}")));
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        write_gz_lines(&dir.path().join(format!("{name}.gz")), &lines);
+    }
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    let run_options = RunOptions {
+        histogram: true,
+        ..Default::default()
+    };
+    let options = SieveOptions::builder(["drop"]).build().unwrap();
+    let summary = process_files(&files, &options, total_size, &run_options).unwrap();
+    assert_eq!(summary.removal_histogram, vec![2, 2, 1, 1]);
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let histogram: Vec<&str> = out
+        .lines()
+        .skip_while(|line| *line != "Files by lines removed:")
+        .collect();
+    assert_eq!(
+        histogram,
+        [
+            "Files by lines removed:",
+            "                0           2  ########################################",
+            "             1-10           2  ########################################",
+            "           11-100           1  ####################",
+            "        101-1,000           1  ####################",
+        ]
+    );
+
+    // Off by default
+    let summary = process_files(&files, &options, total_size, &RunOptions::default()).unwrap();
+    assert!(summary.removal_histogram.is_empty());
+}
+
+#[test]
+fn test_output_codec_converts_gzip_to_zstd() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    write_gz_lines(&file_path, &["keep 1", "drop", "keep 2"]);
+    let untouched = dir.path().join("clean.log.gz");
+    write_gz_lines(&untouched, &["keep"]);
+
+    let options = SieveOptions::builder(["drop"])
+        .output_codec(Codec::Zstd)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!((result.read, result.removed), (3, 1));
+    // Converted even though nothing was removed
+    filter_lines(&untouched, &matcher, &options).unwrap();
+
+    assert!(!file_path.exists() && !untouched.exists());
+    let converted = zstd::decode_all(File::open(dir.path().join("app.log.zst")).unwrap()).unwrap();
+    assert_eq!(converted, b"keep 1\nkeep 2\n");
+    assert_eq!(
+        result.bytes_after,
+        std::fs::metadata(dir.path().join("app.log.zst"))
+            .unwrap()
+            .len()
+    );
+    let converted =
+        zstd::decode_all(File::open(dir.path().join("clean.log.zst")).unwrap()).unwrap();
+    assert_eq!(converted, b"keep\n");
+
+    // An existing file under the new name is never overwritten
+    write_gz_lines(&file_path, &["keep", "drop"]);
+    assert!(filter_lines(&file_path, &matcher, &options).is_err());
+    assert_eq!(read_gz_lines(&file_path), vec!["keep", "drop"]);
+
+    assert_eq!(
+        Codec::Plain.rename(Path::new("a.log.gz"), Codec::Gzip),
+        Path::new("a.log")
+    );
+    assert_eq!(
+        Codec::Xz.rename(Path::new("a.log"), Codec::Plain),
+        Path::new("a.log.xz")
+    );
+}
+
+#[test]
+fn test_parallel_gzip_matches_serial_output() {
+    let dir = tempdir().unwrap();
+    // Several batches of blocks, with a partial block at the end
+    let lines: Vec<String> = (0..40_000)
+        .map(|i| {
+            format!(
+                "{} line {i} {}",
+                if i % 7 == 0 { "DEBUG" } else { "INFO" },
+                i * 31
+            )
+        })
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let serial = dir.path().join("serial.log.gz");
+    let parallel = dir.path().join("parallel.log.gz");
+    write_gz_lines(&serial, &lines);
+    write_gz_lines(&parallel, &lines);
+
+    let matcher = Matcher::new(&["DEBUG".to_string()], MatchKind::Contains).unwrap();
+    let options = SieveOptions::builder(["DEBUG"]).build().unwrap();
+    filter_lines(&serial, &matcher, &options).unwrap();
+    let options = SieveOptions::builder(["DEBUG"])
+        .parallel_gzip(true)
+        .build()
+        .unwrap();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(4)
+        .build()
+        .unwrap();
+    pool.install(|| filter_lines(&parallel, &matcher, &options))
+        .unwrap();
+
+    // A single-member decoder reads it all, so it's one ordinary gzip stream
+    let expected = read_gz_lines(&serial);
+    assert_eq!(expected.len(), (0..40_000).filter(|i| i % 7 != 0).count());
+    assert_eq!(read_gz_lines(&parallel), expected);
+
+    let mut empty = Vec::new();
+    ParallelGzEncoder::new(&mut empty, Compression::default());
+    let mut decoded = Vec::new();
+    GzDecoder::new(&empty[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert!(decoded.is_empty());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_skip_processed_marks_files_and_skips_them_next_time() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    write_gz_lines(&file_path, &["keep", "drop"]);
+    let gather_options = GatherOptions {
+        skip_processed: true,
+        ..Default::default()
+    };
+    let (files, total_size) = gather_gz_files(&[dir.path()], &gather_options).unwrap();
+    assert_eq!(files.len(), 1);
+
+    // A dry run leaves no marker behind
+    let run_options = RunOptions {
+        mark_processed: true,
+        ..Default::default()
+    };
+    let dry_run = SieveOptions::builder(["drop"])
+        .dry_run(true)
+        .build()
+        .unwrap();
+    process_files(&files, &dry_run, total_size, &run_options).unwrap();
+    assert_eq!(xattr::get(&file_path, PROCESSED_XATTR).unwrap(), None);
+
+    let options = SieveOptions::builder(["drop"]).build().unwrap();
+    process_files(&files, &options, total_size, &run_options).unwrap();
+    let marker = xattr::get(&file_path, PROCESSED_XATTR).unwrap().unwrap();
+    assert!(chrono::DateTime::parse_from_rfc3339(std::str::from_utf8(&marker).unwrap()).is_ok());
+
+    assert!(
+        gather_gz_files(&[dir.path()], &gather_options)
+            .unwrap()
+            .0
+            .is_empty()
+    );
+    assert!(
+        gather_gz_files(&[&file_path], &gather_options)
+            .unwrap()
+            .0
+            .is_empty()
+    );
+    assert_eq!(
+        gather_gz_files(&[dir.path()], &GatherOptions::default())
+            .unwrap()
+            .0
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_benchmark_reports_throughput_without_changing_files() {
+    let dir = tempdir().unwrap();
+    for name in ["a.log.gz", "b.log.gz", "c.log.gz"] {
+        write_gz_lines(&dir.path().join(name), &["keep", "drop", "keep"]);
+    }
+    let before: Vec<_> = ["a.log.gz", "b.log.gz", "c.log.gz"]
+        .map(|name| std::fs::read(dir.path().join(name)).unwrap())
+        .into();
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--benchmark",
+    ]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        (summary.total_lines_read, summary.total_lines_removed),
+        (9, 3)
+    );
+    assert_eq!(summary.file_seconds.len(), 3);
+    assert_eq!(cli::exit_status(&args, &summary), 0);
+
+    let mut out = Vec::new();
+    cli::report_summary(&mut out, &args, &summary).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("Benchmarked 3 files"), "{out}");
+    assert!(out.contains(" MB/s, ") && out.contains(" lines/s"), "{out}");
+    assert!(
+        out.contains("Per-file latency: p50 ") && out.contains("ms, max "),
+        "{out}"
+    );
+
+    let after: Vec<_> = ["a.log.gz", "b.log.gz", "c.log.gz"]
+        .map(|name| std::fs::read(dir.path().join(name)).unwrap())
+        .into();
+    assert_eq!(after, before);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn test_strict_rejects_an_invalid_locale() {
+    let args = cli::parse_args_from(vec!["sieve", "/logs", "x", "--locale", "xx-nowhere"]);
+    assert!(cli::check_locale(&args).is_ok());
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        "/logs",
+        "x",
+        "--locale",
+        "xx-nowhere",
+        "--strict",
+    ]);
+    assert!(matches!(
+        cli::check_locale(&args),
+        Err(SieveError::Strict(_))
+    ));
+}
+
+#[test]
+fn test_strict_fails_the_run_on_a_failing_file() {
+    let dir = tempdir().unwrap();
+    std::fs::write(dir.path().join("broken.gz"), b"not gzip at all").unwrap();
+    write_gz_lines(&dir.path().join("good.gz"), &["keep", "drop"]);
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    let run_options = RunOptions {
+        sequential: true,
+        ..Default::default()
+    };
+
+    let lenient = SieveOptions::builder(["drop"])
+        .dry_run(true)
+        .build()
+        .unwrap();
+    let summary = process_files(&files, &lenient, total_size, &run_options).unwrap();
+    assert_eq!(summary.files_failed, 1);
+
+    let strict = SieveOptions::builder(["drop"])
+        .dry_run(true)
+        .strict(true)
+        .build()
+        .unwrap();
+    let result = process_files(&files, &strict, total_size, &run_options);
+    assert!(matches!(result, Err(SieveError::FileFailed { .. })));
+
+    // A listed file that isn't there is an error too, rather than skipped
+    let list = format!("{}\n", dir.path().join("missing.gz").display());
+    assert!(read_file_list(list.as_bytes(), false).unwrap().0.is_empty());
+    assert!(matches!(
+        read_file_list(list.as_bytes(), true),
+        Err(SieveError::Strict(_))
+    ));
+}
+
+#[test]
+fn test_strict_accepts_patterns_inside_longer_matches() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["foobar x", "foobar y", "other"]);
+    let files = vec![(file_path.clone(), 0)];
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "foo",
+        "foobar",
+        "--strict",
+    ]);
+    let options = args.sieve_options(dir.path()).unwrap();
+    let summary = process_files(&files, &options, 0, &RunOptions::default()).unwrap();
+    assert!(cli::unused_patterns(&summary).is_empty());
+    // Only whether each matched is kept, not how often
+    assert_eq!(
+        summary.pattern_hits,
+        [("foo".to_string(), 1), ("foobar".to_string(), 1)]
+    );
+    assert_eq!(summary.total_lines_removed, 2);
+    assert_eq!(read_gz_lines(&file_path), ["other"]);
+}
+
+#[test]
+fn test_prompt_above_skips_large_files_on_no() {
+    let files = vec![
+        (PathBuf::from("small.gz"), 100),
+        (PathBuf::from("huge.gz"), 5_000),
+        (PathBuf::from("big.gz"), 2_000),
+    ];
+    let confirm = |answers: &str, is_terminal, assume_yes| {
+        let mut out = Vec::new();
+        let (kept, total_size) = cli::confirm_large_files(
+            &mut answers.as_bytes(),
+            &mut out,
+            files.clone(),
+            1_000,
+            is_terminal,
+            assume_yes,
+        )
+        .unwrap();
+        let kept: Vec<_> = kept.into_iter().map(|(path, _)| path).collect();
+        (kept, total_size, String::from_utf8(out).unwrap())
+    };
+
+    let (kept, total_size, prompt) = confirm("n\n", true, false);
+    assert_eq!(kept, [PathBuf::from("small.gz")]);
+    assert_eq!(total_size, 100);
+    assert!(prompt.starts_with("2 files are larger than 1000 B:\n  huge.gz (4.88 KiB)\n"));
+
+    let (kept, total_size, _) = confirm("y\n", true, false);
+    assert_eq!(kept.len(), 3);
+    assert_eq!(total_size, 7_100);
+
+    // Asked one by one
+    let (kept, _, prompt) = confirm("a\nn\nyes\n", true, false);
+    assert_eq!(kept, [PathBuf::from("small.gz"), PathBuf::from("big.gz")]);
+    assert!(prompt.contains("Process huge.gz (4.88 KiB)? [y/N] "));
+
+    // Without a terminal, only --yes lets them through
+    assert_eq!(confirm("", false, false).0, [PathBuf::from("small.gz")]);
+    assert_eq!(confirm("", false, true).0.len(), 3);
+}
+
+#[test]
+fn test_dedup_consecutive_collapses_runs_of_kept_lines() {
+    let input = "a\na\na\nDEBUG x\na\nb\nb\nDEBUG y\nDEBUG y\na\nb";
+    let options = SieveOptions::builder(["DEBUG"])
+        .dedup_consecutive(true)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+
+    let mut out = Vec::new();
+    let mut collapsed = 0;
+    let stream = super::Stream {
+        at_start: true,
+        diff: None,
+        collapsed: Some(&mut collapsed),
+    };
+    let counts = super::sieve_stream_with(
+        input.as_bytes(),
+        &mut out,
+        "test",
+        &matcher,
+        &options,
+        stream,
+    )
+    .unwrap();
+    // The DEBUG lines between the a's don't break their run
+    assert_eq!(String::from_utf8(out).unwrap(), "a\nb\na\nb");
+    assert_eq!(counts, (11, 3));
+    assert_eq!(collapsed, 4);
+
+    // Redacted lines are still written, so they end a run
+    let options = SieveOptions::builder(["secret"])
+        .mode(Mode::Redact)
+        .replacement("***")
+        .dedup_consecutive(true)
+        .build()
+        .unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let mut out = Vec::new();
+    sieve_stream(
+        "x\nx\nsecret\nx\n".as_bytes(),
+        &mut out,
+        "test",
+        &matcher,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "x\n***\nx\n");
+}
+
+#[test]
+fn test_dedup_consecutive_rewrites_files_and_counts_collapsed_lines() {
+    let dir = tempdir().unwrap();
+    let repeats = dir.path().join("repeats.gz");
+    write_gz_lines(&repeats, &["start", "same", "same", "same", "end"]);
+    let removes = dir.path().join("removes.gz");
+    write_gz_lines(&removes, &["x", "noise", "x", "y"]);
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "noise",
+        "--dedup-consecutive",
+    ]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+
+    // Nothing matched in the first file, but it's rewritten all the same
+    assert_eq!(read_gz_lines(&repeats), ["start", "same", "end"]);
+    assert_eq!(read_gz_lines(&removes), ["x", "y"]);
+    assert_eq!(summary.total_lines_read, 9);
+    assert_eq!(summary.total_lines_removed, 1);
+    assert_eq!(summary.total_lines_collapsed, 3);
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(
+        out.contains(
+            "Removed 1 lines from a total of 9 lines read.\nCollapsed 3 repeated lines.\n"
+        )
+    );
+}
+
+#[test]
+fn test_trim_matches_without_surrounding_whitespace() {
+    let input = "  ERROR  \n\tERROR\n  ERROR: disk full\n   keep me  \n";
+    let sieve = |options: SieveOptions| {
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let mut out = Vec::new();
+        let counts = sieve_stream(input.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+        (String::from_utf8(out).unwrap(), counts)
+    };
+
+    let exact = |trim| {
+        SieveOptions::builder(["ERROR"])
+            .match_kind(MatchKind::Exact)
+            .trim(trim)
+            .build()
+            .unwrap()
+    };
+    assert_eq!(sieve(exact(false)), (input.to_string(), (4, 0)));
+    // Only the kept lines' original whitespace is written
+    assert_eq!(
+        sieve(exact(true)),
+        ("  ERROR: disk full\n   keep me  \n".to_string(), (4, 2))
+    );
+
+    let starts_with = SieveOptions::builder(["keep"])
+        .match_kind(MatchKind::StartsWith)
+        .mode(Mode::Keep)
+        .trim(true)
+        .build()
+        .unwrap();
+    assert_eq!(sieve(starts_with), ("   keep me  \n".to_string(), (4, 3)));
+
+    // Redacting replaces the trimmed text and leaves the whitespace around it
+    let redact = SieveOptions::builder(["ERROR"])
+        .match_kind(MatchKind::Exact)
+        .mode(Mode::Redact)
+        .replacement("***")
+        .trim(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        sieve(redact).0,
+        "  ***  \n\t***\n  ERROR: disk full\n   keep me  \n"
+    );
+}
+
+#[test]
+fn test_limit_processes_only_the_first_files_by_path() {
+    let dir = tempdir().unwrap();
+    for name in ["e.gz", "b.gz", "d.gz", "a.gz", "c.gz"] {
+        write_gz_lines(&dir.path().join(name), &["keep", "drop"]);
+    }
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--limit",
+        "3",
+    ]);
+    assert_eq!(args.limit, Some(3));
+    let (files, _) = gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let (files, total_size) = limit_files(files, 3);
+    let names: Vec<_> = files
+        .iter()
+        .map(|(path, _)| path.file_name().unwrap())
+        .collect();
+    assert_eq!(names, ["a.gz", "b.gz", "c.gz"]);
+    assert_eq!(total_size, files.iter().map(|(_, size)| size).sum::<u64>());
+
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(summary.files_processed, 3);
+    assert_eq!(read_gz_lines(&dir.path().join("c.gz")), ["keep"]);
+    assert_eq!(read_gz_lines(&dir.path().join("d.gz")), ["keep", "drop"]);
+}
+
+#[test]
+fn test_patterns_are_deduplicated_and_empty_ones_dropped() {
+    let options = SieveOptions::builder(["b", "a", "b", "", "c", "a", ""])
+        .pattern_hits(true)
+        .build()
+        .unwrap();
+    assert_eq!(options.patterns, ["b", "a", "c"]);
+    assert_eq!(options.pattern_hits.as_ref().unwrap().counts().len(), 3);
+
+    // An empty exact pattern matches blank lines only, so it stays
+    let options = SieveOptions::builder(["", "x", ""])
+        .match_kind(MatchKind::Exact)
+        .build()
+        .unwrap();
+    assert_eq!(options.patterns, ["", "x"]);
+
+    let options = SieveOptions::builder(["ERROR", ""]).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let mut out = Vec::new();
+    let counts = sieve_stream(
+        "ok\nERROR\nfine\n".as_bytes(),
+        &mut out,
+        "test",
+        &matcher,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(counts, (3, 1));
+    assert_eq!(String::from_utf8(out).unwrap(), "ok\nfine\n");
+}
+
+#[test]
+fn test_empty_pattern_removes_nothing() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.gz");
+    write_gz_lines(&file_path, &["one", "two", "three"]);
+
+    for mode in ["remove", "keep"] {
+        let args = cli::parse_args_from(vec![
+            "sieve",
+            &dir.path().to_string_lossy(),
+            "",
+            "--mode",
+            mode,
+        ]);
+        // Only warned about without --strict
+        assert!(cli::check_patterns(&args).is_ok());
+        let (files, total_size) =
+            gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+        let summary = super::process_files(
+            &files,
+            &args.sieve_options(dir.path()).unwrap(),
+            total_size,
+            &args.run_options().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(summary.total_lines_read, 3);
+        assert_eq!(summary.total_lines_removed, 0);
+        assert_eq!(read_gz_lines(&file_path), ["one", "two", "three"]);
+    }
+
+    let args = cli::parse_args_from(vec!["sieve", "/logs", "", "--strict"]);
+    assert!(matches!(
+        cli::check_patterns(&args),
+        Err(SieveError::Strict(_))
+    ));
+    let args = cli::parse_args_from(vec!["sieve", "/logs", "", "--exact", "--strict"]);
+    assert!(cli::check_patterns(&args).is_ok());
+}
+
+#[test]
+fn test_decode_escapes() {
+    assert_eq!(
+        cli::decode_escapes(r"a\tb\n\r\0\\\x41\x7e").unwrap(),
+        "a\tb\n\r\0\\A~"
+    );
+    assert_eq!(cli::decode_escapes("plain").unwrap(), "plain");
+    assert!(cli::decode_escapes(r"\x80").is_err());
+    assert!(cli::decode_escapes(r"\x4").is_err());
+    assert!(cli::decode_escapes(r"\x+1").is_err());
+    assert!(cli::decode_escapes(r"\q").is_err());
+    assert!(cli::decode_escapes("trailing\\").is_err());
+}
+
+#[test]
+fn test_decode_escapes_matches_tabs_in_lines() {
+    let input = "GET\t/health\t200\nGET /health 200\nPOST\t/login\t200\n";
+    let sieve = |extra: &[&str]| {
+        let mut argv = vec!["sieve", "/logs", r"GET\t/health"];
+        argv.extend_from_slice(extra);
+        let options = cli::parse_args_from(argv)
+            .sieve_options(Path::new("/logs"))
+            .unwrap();
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let mut out = Vec::new();
+        sieve_stream(input.as_bytes(), &mut out, "test", &matcher, &options).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    // Without decoding, the backslash and t are looked for literally
+    assert_eq!(sieve(&[]), input);
+    assert_eq!(
+        sieve(&["--decode-escapes"]),
+        "GET /health 200\nPOST\t/login\t200\n"
+    );
+
+    let args = cli::parse_args_from(vec!["sieve", "/logs", r"\xzz", "--decode-escapes"]);
+    assert!(matches!(
+        args.sieve_options(Path::new("/logs")),
+        Err(SieveError::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn test_lock_run_refuses_a_second_run() {
+    let dir = tempdir().unwrap();
+    let lock_path = dir.path().join("sieve.lock");
+
+    let (locked_sender, locked) = std::sync::mpsc::channel();
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    let holder = {
+        let lock_path = lock_path.clone();
+        std::thread::spawn(move || {
+            let _lock = lock_run(&lock_path, false).unwrap();
+            locked_sender.send(()).unwrap();
+            released.recv().unwrap();
+        })
+    };
+    locked.recv().unwrap();
+
+    let started = Instant::now();
+    assert!(matches!(
+        lock_run(&lock_path, false),
+        Err(SieveError::Locked(path)) if path == lock_path.display().to_string()
+    ));
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Waiting picks the lock up as soon as the first run lets go of it
+    let waiter = {
+        let lock_path = lock_path.clone();
+        std::thread::spawn(move || lock_run(&lock_path, true).map(drop))
+    };
+    release.send(()).unwrap();
+    holder.join().unwrap();
+    waiter.join().unwrap().unwrap();
+    assert!(lock_run(&lock_path, false).is_ok());
+}
+
+#[test]
+fn test_summary_counts_processed_skipped_and_failed_files() {
+    let dir = tempdir().unwrap();
+    write_gz_lines(&dir.path().join("a.gz"), &["keep", "drop"]);
+    write_gz_lines(&dir.path().join("b.gz"), &["keep"]);
+    write_gz_lines(&dir.path().join("skip.gz"), &["drop"]);
+    std::fs::write(dir.path().join("broken.gz"), b"not gzip at all").unwrap();
+    // Not a supported format, so never a candidate to skip
+    std::fs::write(dir.path().join("notes.txt"), b"drop").unwrap();
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--exclude",
+        "skip.*",
+    ]);
+    let gathered = gather_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    assert_eq!(gathered.files.len(), 3);
+    assert_eq!(gathered.skipped, 1);
+
+    let mut summary = super::process_files(
+        &gathered.files,
+        &args.sieve_options(dir.path()).unwrap(),
+        gathered.total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    summary.files_skipped = gathered.skipped;
+    assert_eq!(summary.files_processed, 2);
+    assert_eq!(summary.files_failed, 1);
+
+    let json: serde_json::Value = serde_json::from_str(&cli::json_summary(&summary)).unwrap();
+    assert_eq!(json["files_processed"], 2);
+    assert_eq!(json["files_skipped"], 1);
+    assert_eq!(json["files_failed"], 1);
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    assert!(
+        String::from_utf8(out)
+            .unwrap()
+            .contains("\n2 files processed, 1 skipped, 1 failed.\n")
+    );
+}
+
+#[test]
+fn test_fsync_rewrites_files() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    write_gz_lines(&file_path, &["keep 1", "drop", "keep 2"]);
+    let out_dir = dir.path().join("out");
+
+    let options = SieveOptions::builder(["drop"]).fsync(true).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!((read, removed), (3, 1));
+    assert_eq!(read_gz_lines(&file_path), ["keep 1", "keep 2"]);
+
+    // Written below an output directory, the original stays as it was
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "keep 2",
+        "--fsync",
+        "--output-dir",
+        &out_dir.to_string_lossy(),
+    ]);
+    let options = args.sieve_options(dir.path()).unwrap();
+    assert!(options.fsync);
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!(read_gz_lines(&out_dir.join("app.log.gz")), ["keep 1"]);
+    assert_eq!(read_gz_lines(&file_path), ["keep 1", "keep 2"]);
+}
+
+#[test]
+fn test_keep_temp_on_error_keeps_partial_output() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz");
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(b"ok 1\ndrop\nok 2\nbad \xff\xfe bytes\nok 3\n")
+            .unwrap();
+        gz.finish().unwrap();
+    }
+    let original = std::fs::read(&file_path).unwrap();
+    let partial_path = dir.path().join("app.log.gz.sieve-partial");
+
+    let sieve = |keep_temp_on_error| {
+        let options = SieveOptions::builder(["drop"])
+            .keep_temp_on_error(keep_temp_on_error)
+            .build()
+            .unwrap();
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        filter_lines(&file_path, &matcher, &options)
+    };
+
+    assert!(matches!(sieve(false), Err(SieveError::LineRead { .. })));
+    assert!(!partial_path.exists());
+
+    assert!(matches!(sieve(true), Err(SieveError::LineRead { .. })));
+    // Everything sieved before the bad line, and the original as it was
+    assert_eq!(read_gz_lines(&partial_path), ["ok 1", "ok 2"]);
+    assert_eq!(std::fs::read(&file_path).unwrap(), original);
+    let leftovers: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(leftovers.len(), 2);
+}
+
+#[test]
+fn test_zstd_round_trip() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.log.zst");
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut encoder = zstd::Encoder::new(file, 3).unwrap().auto_finish();
+        writeln!(encoder, "line 1").unwrap();
+        writeln!(encoder, "line 2 pattern").unwrap();
+        writeln!(encoder, "line 3").unwrap();
+    }
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    assert_eq!(files.len(), 1);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+
+    let decoder = zstd::Decoder::new(File::open(&file_path).unwrap()).unwrap();
+    let lines: Vec<String> = BufReader::new(decoder)
+        .lines()
+        .map(|l| l.unwrap())
+        .collect();
+    assert_eq!(lines, vec!["line 1", "line 3"]);
+}
+
+#[test]
+fn test_codec_from_path() {
+    assert_eq!(
+        Codec::from_path(Path::new("a.log.gz"), &[]),
+        Some(Codec::Gzip)
+    );
+    assert_eq!(
+        Codec::from_path(Path::new("a.log.zst"), &[]),
+        Some(Codec::Zstd)
+    );
+    assert_eq!(
+        Codec::from_path(Path::new("a.log.bz2"), &[]),
+        Some(Codec::Bzip2)
+    );
+    assert_eq!(
+        Codec::from_path(Path::new("a.log.xz"), &[]),
+        Some(Codec::Xz)
+    );
+    assert_eq!(Codec::from_path(Path::new("a.log"), &[]), None);
+    assert_eq!(
+        Codec::from_path(Path::new("a.log"), &["log".to_string()]),
+        Some(Codec::Plain)
+    );
+}
+
+#[test]
+fn test_bzip2_round_trip() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.log.bz2");
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut encoder = bzip2::write::BzEncoder::new(file, bzip2::Compression::default());
+        writeln!(encoder, "line 1").unwrap();
+        writeln!(encoder, "line 2 pattern").unwrap();
+        writeln!(encoder, "line 3").unwrap();
+    }
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    assert_eq!(files.len(), 1);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+
+    // The rewritten file must still be a complete bzip2 stream
+    let mut decoder = bzip2::read::BzDecoder::new(File::open(&file_path).unwrap());
+    let mut content = String::new();
+    decoder.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "line 1\nline 3\n");
+}
+
+#[test]
+fn test_xz_round_trip() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.log.xz");
+    {
+        let file = File::create(&file_path).unwrap();
+        let mut encoder = xz2::write::XzEncoder::new(file, 6);
+        writeln!(encoder, "line 1").unwrap();
+        writeln!(encoder, "line 2 pattern").unwrap();
+        writeln!(encoder, "line 3").unwrap();
+    }
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    assert_eq!(files.len(), 1);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+
+    let mut decoder = xz2::read::XzDecoder::new(File::open(&file_path).unwrap());
+    let mut content = String::new();
+    decoder.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "line 1\nline 3\n");
+}
+
+#[test]
+fn test_plain_text_files() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log");
+    std::fs::write(&file_path, "line 1\nline 2 pattern\nline 3\n").unwrap();
+    std::fs::write(dir.path().join("notes.md"), "pattern\n").unwrap();
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "pattern",
+        "--extensions",
+        "txt,log",
+    ]);
+    let (files, _) = gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, file_path);
+
+    let matcher = Matcher::new(&args.patterns, args.match_kind()).unwrap();
+    let options = args.sieve_options(dir.path()).unwrap();
+    let FileResult { read, removed, .. } = filter_lines(&file_path, &matcher, &options).unwrap();
+
+    assert_eq!(read, 3);
+    assert_eq!(removed, 1);
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "line 1\nline 3\n"
+    );
+}
+
+#[test]
+fn test_plain_text_files_ignored_by_default() {
+    let dir = tempdir().unwrap();
+    std::fs::write(dir.path().join("app.log"), "line 1\n").unwrap();
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    assert!(files.is_empty());
+}
+
+#[test]
+fn test_detect_magic_finds_misnamed_gzip() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("app.log.gz.old");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern"]);
+    std::fs::write(dir.path().join("notes.txt"), "not gzip").unwrap();
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    assert!(files.is_empty());
+
+    let options = GatherOptions {
+        detect_magic: true,
+        ..Default::default()
+    };
+    let (files, _) = gather_gz_files(&[dir.path()], &options).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, file_path);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    let FileResult { read, removed, .. } =
+        filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+    assert_eq!(read, 2);
+    assert_eq!(removed, 1);
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1"]);
+}
+
+#[test]
+fn test_compression_level() {
+    let dir = tempdir().unwrap();
+    let lines: Vec<String> = (0..2000)
+        .map(|i| format!("line {i} with some fairly repetitive content"))
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let patterns = vec!["line 0 ".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+
+    let mut sizes = Vec::new();
+    for level in [0, 9] {
+        let file_path = dir.path().join(format!("level{level}.gz"));
+        write_gz_lines(&file_path, &lines);
+        let options = SieveOptions {
+            compression_level: Some(level),
+            ..Default::default()
+        };
+        filter_lines(&file_path, &matcher, &options).unwrap();
+        assert_eq!(read_gz_lines(&file_path).len(), 1999);
+        sizes.push(std::fs::metadata(&file_path).unwrap().len());
+    }
+
+    assert!(sizes[0] > sizes[1]);
+}
+
+#[test]
+fn test_compression_level_out_of_range() {
+    let result = Args::try_parse_from(vec!["sieve", "/tmp", "x", "--compression-level", "10"]);
+    assert!(result.is_err());
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "x", "--compression-level", "9"]);
+    assert_eq!(args.compression_level, Some(9));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_preserve_metadata() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern"]);
+    std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(&file_path, mtime).unwrap();
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "pattern"]);
+    assert!(args.preserve_metadata);
+    let matcher = Matcher::new(&args.patterns, args.match_kind()).unwrap();
+    let options = args.sieve_options(dir.path()).unwrap();
+    filter_lines(&file_path, &matcher, &options).unwrap();
+
+    let metadata = std::fs::metadata(&file_path).unwrap();
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1"]);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&metadata),
+        mtime
+    );
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "pattern", "--no-preserve-metadata"]);
+    assert!(!args.preserve_metadata);
+
+    // The permissions come along either way, only the times are left behind
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern"]);
+    std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    filetime::set_file_mtime(&file_path, mtime).unwrap();
+    let options = args.sieve_options(dir.path()).unwrap();
+    filter_lines(&file_path, &matcher, &options).unwrap();
+
+    let metadata = std::fs::metadata(&file_path).unwrap();
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1"]);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+    assert_ne!(
+        filetime::FileTime::from_last_modification_time(&metadata),
+        mtime
+    );
+}
+
+#[test]
+fn test_replaced_atomically_without_leftover_temp_files() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern", "line 3"]);
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    filter_lines(&file_path, &matcher, &SieveOptions::default()).unwrap();
+
+    assert_eq!(read_gz_lines(&file_path), vec!["line 1", "line 3"]);
+
+    // The temp file was renamed over the original rather than left beside it
+    let entries: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, vec![std::ffi::OsString::from("test.gz")]);
+}
+
+#[test]
+fn test_unchanged_files_are_not_rewritten() {
+    let dir = tempdir().unwrap();
+    let clean = dir.path().join("clean.gz");
+    let dirty = dir.path().join("dirty.gz");
+    write_gz_lines(&clean, &["line 1", "line 2"]);
+    write_gz_lines(&dirty, &["line 1", "line 2 pattern"]);
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(&clean, mtime).unwrap();
+    filetime::set_file_mtime(&dirty, mtime).unwrap();
+    let clean_bytes = std::fs::read(&clean).unwrap();
+
+    let patterns = vec!["pattern".to_string()];
+    let matcher = Matcher::new(&patterns, MatchKind::Contains).unwrap();
+    filter_lines(&clean, &matcher, &SieveOptions::default()).unwrap();
+    filter_lines(&dirty, &matcher, &SieveOptions::default()).unwrap();
+
+    let clean_metadata = std::fs::metadata(&clean).unwrap();
+    assert_eq!(std::fs::read(&clean).unwrap(), clean_bytes);
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&clean_metadata),
+        mtime
+    );
+
+    let dirty_metadata = std::fs::metadata(&dirty).unwrap();
+    assert_eq!(read_gz_lines(&dirty), vec!["line 1"]);
+    assert_ne!(
+        filetime::FileTime::from_last_modification_time(&dirty_metadata),
+        mtime
+    );
+}
+
+#[test]
+fn test_rename_and_copy_replacement_match() {
+    let dir = tempdir().unwrap();
+    let content: String = (0..250_000)
+        .map(|i| format!("line {i:08} of a ten megabyte file......\n"))
+        .collect();
+    assert!(content.len() >= 10 * 1024 * 1024);
+
+    let renamed = dir.path().join("renamed.log");
+    let copied = dir.path().join("copied.log");
+    std::fs::write(&renamed, "old").unwrap();
+    std::fs::write(&copied, "old").unwrap();
+
+    let temp_file = super::create_temp_file_near(&renamed).unwrap();
+    std::fs::write(temp_file.path(), &content).unwrap();
+    super::replace_file(temp_file, &renamed, false, false).unwrap();
+
+    let temp_file = super::create_temp_file_near(&copied).unwrap();
+    std::fs::write(temp_file.path(), &content).unwrap();
+    super::copy_file_into_place(temp_file.path(), &copied, false).unwrap();
+
+    assert_eq!(std::fs::read(&renamed).unwrap(), content.as_bytes());
+    assert_eq!(std::fs::read(&copied).unwrap(), content.as_bytes());
+}
+
+#[test]
+fn test_max_depth() {
+    let dir = tempdir().unwrap();
+    let level1 = dir.path().join("level1");
+    let level2 = level1.join("level2");
+    std::fs::create_dir_all(&level2).unwrap();
+    File::create(dir.path().join("top.gz")).unwrap();
+    File::create(level1.join("one.gz")).unwrap();
+    File::create(level2.join("two.gz")).unwrap();
+
+    let count_at = |max_depth| {
+        let options = GatherOptions {
+            max_depth,
+            ..Default::default()
+        };
+        gather_gz_files(&[dir.path()], &options).unwrap().0.len()
+    };
+
+    assert_eq!(count_at(Some(0)), 1);
+    assert_eq!(count_at(Some(1)), 2);
+    assert_eq!(count_at(Some(2)), 3);
+    assert_eq!(count_at(None), 3);
+}
+
+#[test]
+fn test_include_exclude_globs() {
+    let dir = tempdir().unwrap();
+    for name in [
+        "app-1.log.gz",
+        "app-2-debug.gz",
+        "web-1.log.gz",
+        "db-1.log.gz",
+    ] {
+        File::create(dir.path().join(name)).unwrap();
+    }
+
+    let names_for = |extra: &[&str]| {
+        let mut argv = vec!["sieve", "/tmp"];
+        argv.extend_from_slice(extra);
+        let args = cli::parse_args_from(argv);
+        let (files, _) = gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+        let mut names: Vec<String> = files
+            .iter()
+            .map(|(p, _)| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        names_for(&["--include", "app-*"]),
+        vec!["app-1.log.gz", "app-2-debug.gz"]
+    );
+    assert_eq!(
+        names_for(&["--include", "app-*", "--include", "web-*"]),
+        vec!["app-1.log.gz", "app-2-debug.gz", "web-1.log.gz"]
+    );
+    assert_eq!(
+        names_for(&["--exclude", "*-debug.gz", "--exclude", "db-*"]),
+        vec!["app-1.log.gz", "web-1.log.gz"]
+    );
+    // A file matching both include and exclude is skipped
+    assert_eq!(
+        names_for(&["--include", "app-*", "--exclude", "*-debug.gz"]),
+        vec!["app-1.log.gz"]
+    );
+}
+
+#[test]
+fn test_invalid_glob() {
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "--include", "app-[*"]);
+    assert!(matches!(
+        args.gather_options(),
+        Err(SieveError::InvalidGlob { .. })
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_follow_symlinks() {
+    let storage = tempdir().unwrap();
+    write_gz_lines(&storage.path().join("shared.gz"), &["line 1"]);
+
+    let dir = tempdir().unwrap();
+    File::create(dir.path().join("local.gz")).unwrap();
+    std::os::unix::fs::symlink(storage.path(), dir.path().join("shared")).unwrap();
+    // A link back to the root would loop forever without WalkDir's loop detection
+    std::os::unix::fs::symlink(dir.path(), dir.path().join("loop")).unwrap();
+
+    let (files, _) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    assert_eq!(files.len(), 1);
+
+    let options = GatherOptions {
+        follow_symlinks: true,
+        ..Default::default()
+    };
+    let (files, _) = gather_gz_files(&[dir.path()], &options).unwrap();
+    let mut names: Vec<_> = files.iter().map(|(p, _)| p.file_name().unwrap()).collect();
+    names.sort();
+    assert_eq!(names, vec!["local.gz", "shared.gz"]);
+}
+
+#[test]
+fn test_json_summary() {
+    let dir = tempdir().unwrap();
+    write_gz_lines(&dir.path().join("a.gz"), &["keep", "drop 1"]);
+    write_gz_lines(&dir.path().join("b.gz"), &["drop 2", "keep", "keep"]);
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--format",
+        "json",
+    ]);
+    assert_eq!(args.format, OutputFormat::Json);
+
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let mut summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    summary.elapsed_seconds = 0.5;
+
+    let json: serde_json::Value = serde_json::from_str(&cli::json_summary(&summary)).unwrap();
+    assert_eq!(json["total_lines_read"], 5);
+    assert_eq!(json["total_lines_removed"], 2);
+    assert_eq!(json["files_processed"], 2);
+    assert_eq!(json["files_failed"], 0);
+    assert_eq!(json["elapsed_seconds"], 0.5);
+}
+
+#[test]
+fn test_quiet_mode_produces_no_output() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("test.gz");
+    write_gz_lines(&file_path, &["line 1", "line 2 pattern"]);
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "pattern",
+        "--quiet",
+    ]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+
+    // Counts are still reported back even though nothing is printed
+    assert_eq!(summary.total_lines_read, 2);
+    assert_eq!(summary.total_lines_removed, 1);
+    assert_eq!(summary.files_processed, 1);
+    let mut out = Vec::new();
+    cli::report_summary(&mut out, &args, &summary).unwrap();
+    assert!(out.is_empty());
+
+    let args = cli::parse_args_from(vec!["sieve", "/tmp", "pattern"]);
+    cli::report_summary(&mut out, &args, &summary).unwrap();
+    assert!(!out.is_empty());
+}
+
+/// A directory with two valid gzip files and one corrupt one
+fn dir_with_one_invalid_file() -> tempfile::TempDir {
+    let dir = tempdir().unwrap();
+    write_gz_lines(&dir.path().join("a.gz"), &["keep", "drop"]);
+    write_gz_lines(&dir.path().join("c.gz"), &["drop", "keep"]);
+    std::fs::write(dir.path().join("b.gz"), b"not a valid gz file").unwrap();
+    dir
+}
+
+#[test]
+fn test_continue_on_error_counts_failed_files() {
+    let dir = dir_with_one_invalid_file();
+    let args = cli::parse_args_from(vec!["sieve", &dir.path().to_string_lossy(), "drop"]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(summary.files_processed, 2);
+    assert_eq!(summary.files_failed, 1);
+    assert_eq!(summary.total_lines_removed, 2);
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    assert!(
+        String::from_utf8(out)
+            .unwrap()
+            .contains("Failed to process 1 files")
+    );
+}
+
+#[test]
+fn test_failed_files_are_listed_with_their_errors() {
+    let dir = dir_with_one_invalid_file();
+    let args = cli::parse_args_from(vec!["sieve", &dir.path().to_string_lossy(), "drop"]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+
+    let bad_file = dir.path().join("b.gz");
+    assert_eq!(summary.failures.len(), 1);
+    assert_eq!(summary.failures[0].0, bad_file);
+    assert!(matches!(summary.failures[0].1, SieveError::LineRead { .. }));
+
+    let mut out = Vec::new();
+    cli::print_summary(&mut out, &summary, &Mode::Remove, "en").unwrap();
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.contains(&format!("  {}: ", bad_file.display())));
+}
+
+#[test]
+fn test_fail_fast_aborts_on_error() {
+    let dir = dir_with_one_invalid_file();
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &dir.path().to_string_lossy(),
+        "drop",
+        "--fail-fast",
+        "--threads",
+        "1",
+    ]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+
+    let result = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    );
+
+    match result {
+        Err(SieveError::FileFailed { path, .. }) => assert!(path.ends_with("b.gz")),
+        other => panic!("Expected FileFailed error, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_exit_status() {
+    let dir = tempdir().unwrap();
+    write_gz_lines(&dir.path().join("a.gz"), &["keep", "drop"]);
+
+    let run = |extra: &[&str]| {
+        let root = dir.path().to_string_lossy().to_string();
+        let mut argv = vec!["sieve", &root];
+        argv.extend_from_slice(extra);
+        let args = cli::parse_args_from(argv);
+        let (files, total_size) =
+            gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+        let summary = super::process_files(
+            &files,
+            &args.sieve_options(dir.path()).unwrap(),
+            total_size,
+            &args.run_options().unwrap(),
+        )
+        .unwrap();
+        cli::exit_status(&args, &summary)
+    };
+
+    assert_eq!(run(&["absent"]), 0);
+    assert_eq!(run(&["drop", "--dry-run", "--no-exit-status"]), 0);
+    assert_eq!(run(&["drop"]), EXIT_LINES_REMOVED);
+    // Already removed, so nothing changes the second time
+    assert_eq!(run(&["drop"]), 0);
+}
+
+#[test]
+fn test_exit_status_for_failed_files() {
+    let dir = dir_with_one_invalid_file();
+    let args = cli::parse_args_from(vec!["sieve", &dir.path().to_string_lossy(), "drop"]);
+    let (files, total_size) =
+        gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(dir.path()).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(cli::exit_status(&args, &summary), 1);
+}
+
+#[test]
+fn test_multiple_roots() {
+    let first = tempdir().unwrap();
+    let second = tempdir().unwrap();
+    write_gz_lines(&first.path().join("a.gz"), &["keep", "drop"]);
+    write_gz_lines(&second.path().join("b.gz"), &["drop", "keep"]);
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &first.path().to_string_lossy(),
+        "drop",
+        "--root",
+        &second.path().to_string_lossy(),
+    ]);
+    let roots = args.roots();
+    assert_eq!(roots.len(), 2);
+
+    let (files, total_size) = gather_gz_files(&roots, &args.gather_options().unwrap()).unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(total_size, files.iter().map(|(_, size)| size).sum::<u64>());
+
+    let summary = super::process_files(
+        &files,
+        &args.sieve_options(&roots[0]).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(summary.total_lines_removed, 2);
+    assert_eq!(read_gz_lines(&first.path().join("a.gz")), vec!["keep"]);
+    assert_eq!(read_gz_lines(&second.path().join("b.gz")), vec!["keep"]);
+}
+
+#[test]
+fn test_overlapping_roots_are_deduplicated() {
+    let dir = tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("nested")).unwrap();
+    write_gz_lines(&dir.path().join("a.gz"), &["line"]);
+    write_gz_lines(&dir.path().join("nested/b.gz"), &["line"]);
+
+    let roots = [dir.path().to_path_buf(), dir.path().join("nested")];
+    let (files, _) = gather_gz_files(&roots, &GatherOptions::default()).unwrap();
+    assert_eq!(files.len(), 2);
+}
+
+#[test]
+fn test_read_file_list() {
+    let dir = tempdir().unwrap();
+    let listed = dir.path().join("listed.gz");
+    let unlisted = dir.path().join("unlisted.gz");
+    write_gz_lines(&listed, &["keep", "drop"]);
+    write_gz_lines(&unlisted, &["keep", "drop"]);
+
+    let manifest = format!(
+        "{}\n\n{}\n{}\n",
+        listed.display(),
+        dir.path().join("missing.gz").display(),
+        dir.path().display(),
+    );
+    let (files, total_size) = super::read_file_list(manifest.as_bytes(), false).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, listed);
+    assert_eq!(total_size, std::fs::metadata(&listed).unwrap().len());
+
+    let patterns = vec!["drop".to_string()];
+    super::process_files(
+        &files,
+        &SieveOptions::builder(&patterns).build().unwrap(),
+        total_size,
+        &RunOptions {
+            threads: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(read_gz_lines(&listed), vec!["keep"]);
+    assert_eq!(read_gz_lines(&unlisted), vec!["keep", "drop"]);
+}
+
+#[test]
+fn test_size_filters() {
+    let dir = tempdir().unwrap();
+    std::fs::write(dir.path().join("small.gz"), vec![0; 100]).unwrap();
+    std::fs::write(dir.path().join("medium.gz"), vec![0; 2048]).unwrap();
+    std::fs::write(dir.path().join("large.gz"), vec![0; 8192]).unwrap();
+
+    let gather = |extra: &[&str]| {
+        let root = dir.path().to_string_lossy().to_string();
+        let mut argv = vec!["sieve", &root, "x"];
+        argv.extend_from_slice(extra);
+        let args = cli::parse_args_from(argv);
+        let (files, total_size) =
+            gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+        let mut names: Vec<_> = files
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        (names, total_size)
+    };
+
+    assert_eq!(
+        gather(&["--min-size", "1K"]),
+        (vec!["large.gz".into(), "medium.gz".into()], 10240)
+    );
+    assert_eq!(
+        gather(&["--max-size", "2k"]),
+        (vec!["medium.gz".into(), "small.gz".into()], 2148)
+    );
+    assert_eq!(
+        gather(&["--min-size", "1K", "--max-size", "4K"]),
+        (vec!["medium.gz".into()], 2048)
+    );
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(cli::parse_size("4096"), Ok(4096));
+    assert_eq!(cli::parse_size("512K"), Ok(512 * 1024));
+    assert_eq!(cli::parse_size("10m"), Ok(10 * 1024 * 1024));
+    assert_eq!(cli::parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+    assert!(cli::parse_size("10X").is_err());
+    assert!(cli::parse_size("M").is_err());
+    assert!(cli::parse_size("99999999999T").is_err());
+}
+
+#[test]
+fn test_parse_buffer_size() {
+    assert_eq!(cli::parse_buffer_size("64K"), Ok(64 * 1024));
+    assert_eq!(cli::parse_buffer_size("1024"), Ok(1024));
+    assert!(cli::parse_buffer_size("512").is_err());
+    assert!(cli::parse_buffer_size("big").is_err());
+}
+
+#[test]
+fn test_buffer_size_does_not_change_output() {
+    let dir = tempdir().unwrap();
+    let lines: Vec<String> = (0..20_000)
+        .map(|i| {
+            format!(
+                "{} line {i} {}",
+                if i % 3 == 0 { "DEBUG" } else { "INFO" },
+                "x".repeat(i % 200)
+            )
+        })
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+    let mut outputs = Vec::new();
+    for buffer_size in [MIN_BUFFER_SIZE, 4 * 1024 * 1024] {
+        let file_path = dir.path().join(format!("large{buffer_size}.gz"));
+        write_gz_lines(&file_path, &lines);
+        let options = SieveOptions::builder(["DEBUG"])
+            .buffer_size(buffer_size)
+            .build()
+            .unwrap();
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let result = filter_lines(&file_path, &matcher, &options).unwrap();
+
+        let mut content = Vec::new();
+        GzDecoder::new(File::open(&file_path).unwrap())
+            .read_to_end(&mut content)
+            .unwrap();
+        outputs.push(((result.read, result.removed), content));
+    }
+    assert_eq!(outputs[0].0, (20_000, 6_667));
+    assert_eq!(outputs[0], outputs[1]);
+
+    assert!(
+        SieveOptions::builder(["DEBUG"])
+            .buffer_size(16)
+            .build()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_mmap_matches_normal_reading() {
+    let dir = tempdir().unwrap();
+    // Pseudo-random hex keeps the compressed file well above the mmap threshold
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let lines: Vec<String> = (0..20_000)
+        .map(|i| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            format!("{} {state:016x}", if i % 4 == 0 { "DEBUG" } else { "INFO" })
+        })
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+    let mut outputs = Vec::new();
+    for mmap in [false, true] {
+        let file_path = dir.path().join(format!("mmap-{mmap}.gz"));
+        write_gz_lines(&file_path, &lines);
+        assert!(std::fs::metadata(&file_path).unwrap().len() >= MIN_MMAP_SIZE);
+        let options = SieveOptions::builder(["DEBUG"]).mmap(mmap).build().unwrap();
+        let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+        let result = filter_lines(&file_path, &matcher, &options).unwrap();
+
+        let mut content = Vec::new();
+        GzDecoder::new(File::open(&file_path).unwrap())
+            .read_to_end(&mut content)
+            .unwrap();
+        outputs.push(((result.read, result.removed), content));
+    }
+    assert_eq!(outputs[0].0, (20_000, 5_000));
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn test_parallel_filtering_within_a_file_matches_sequential() {
+    let lines: Vec<String> = (0..60_000)
+        .map(|i| {
+            format!(
+                "{} request {i} {}",
+                if i % 3 == 0 { "DEBUG" } else { "INFO" },
+                "x".repeat(i % 40)
+            )
+        })
+        .collect();
+    let mut input = lines.join("\n").into_bytes();
+    input.extend_from_slice(b"\nDEBUG last line without a newline");
+    assert!(input.len() > 4 * PARALLEL_CHUNK_SIZE);
+
+    let options = SieveOptions::builder(["DEBUG"]).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let mut sequential = Vec::new();
+    let sequential_counts =
+        sieve_stream(&input[..], &mut sequential, "memory", &matcher, &options).unwrap();
+    let mut parallel = Vec::new();
+    let parallel_counts =
+        sieve_stream_parallel(&input[..], &mut parallel, "memory", &matcher, &options, 4).unwrap();
+
+    assert_eq!(parallel_counts, sequential_counts);
+    assert_eq!(parallel_counts, (60_001, 20_001));
+    assert!(
+        parallel == sequential,
+        "parallel output differs from sequential"
+    );
+
+    // And through a whole file, with the threshold forcing the parallel path
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("big.gz");
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    write_gz_lines(&file_path, &lines);
+    let options = SieveOptions::builder(["DEBUG"])
+        .parallel_above(0)
+        .build()
+        .unwrap();
+    let result = filter_lines(&file_path, &matcher, &options).unwrap();
+    assert_eq!((result.read, result.removed), (60_000, 20_000));
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(&file_path).unwrap())
+        .read_to_end(&mut content)
+        .unwrap();
+    let expected: Vec<u8> = lines
+        .iter()
+        .filter(|line| !line.contains("DEBUG"))
+        .flat_map(|line| format!("{line}\n").into_bytes())
+        .collect();
+    assert!(content == expected, "parallel file output differs");
+}
+
+#[test]
+fn test_modification_time_filters() {
+    let dir = tempdir().unwrap();
+    let now = std::time::SystemTime::now();
+    for (name, age_days) in [("fresh.gz", 0), ("week.gz", 7), ("old.gz", 40)] {
+        let path = dir.path().join(name);
+        write_gz_lines(&path, &["line"]);
+        let modified = now - Duration::from_secs(age_days * 24 * 60 * 60);
+        filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(modified)).unwrap();
+    }
+
+    let gather = |extra: &[&str]| {
+        let root = dir.path().to_string_lossy().to_string();
+        let mut argv = vec!["sieve", &root, "x"];
+        argv.extend_from_slice(extra);
+        let args = cli::parse_args_from(argv);
+        let (files, _) = gather_gz_files(&[dir.path()], &args.gather_options().unwrap()).unwrap();
+        let mut names: Vec<_> = files
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(gather(&["--modified-since", "2d"]), vec!["fresh.gz"]);
+    assert_eq!(
+        gather(&["--modified-before", "3d"]),
+        vec!["old.gz", "week.gz"]
+    );
+    assert_eq!(
+        gather(&["--modified-since", "30d", "--modified-before", "24h"]),
+        vec!["week.gz"]
+    );
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(cli::parse_duration("90s"), Ok(Duration::from_secs(90)));
+    assert_eq!(cli::parse_duration("24h"), Ok(Duration::from_secs(86_400)));
+    assert_eq!(cli::parse_duration("7d"), Ok(Duration::from_secs(604_800)));
+    assert!(cli::parse_duration("7").is_err());
+    assert!(cli::parse_duration("7y").is_err());
+    assert!(cli::parse_duration("d").is_err());
+}
+
+#[test]
+fn test_cancelled_run_stops_without_touching_files() {
+    let dir = tempdir().unwrap();
+    for name in ["a.gz", "b.gz", "c.gz"] {
+        write_gz_lines(&dir.path().join(name), &["keep", "drop"]);
+    }
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+
+    let run_options = RunOptions {
+        threads: Some(1),
+        ..Default::default()
+    };
+    run_options.cancelled.store(true, Ordering::Relaxed);
+
+    let patterns = vec!["drop".to_string()];
+    let result = super::process_files(
+        &files,
+        &SieveOptions::builder(&patterns).build().unwrap(),
+        total_size,
+        &run_options,
+    );
+    assert!(matches!(result, Err(SieveError::Cancelled)));
+
+    // Every file is intact and no temp files were left behind
+    for (path, _) in &files {
+        assert_eq!(read_gz_lines(path), vec!["keep", "drop"]);
+    }
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn test_checkpoint_skips_finished_files() {
+    let dir = tempdir().unwrap();
+    let data = dir.path().join("data");
+    std::fs::create_dir(&data).unwrap();
+    let done = data.join("done.gz");
+    let pending = data.join("pending.gz");
+    write_gz_lines(&done, &["keep", "drop"]);
+    write_gz_lines(&pending, &["keep", "drop"]);
+
+    let checkpoint = dir.path().join("checkpoint");
+    std::fs::write(
+        &checkpoint,
+        format!("{}\n", done.canonicalize().unwrap().display()),
+    )
+    .unwrap();
+
+    let args = cli::parse_args_from(vec![
+        "sieve",
+        &data.to_string_lossy(),
+        "drop",
+        "--checkpoint",
+        &checkpoint.to_string_lossy(),
+    ]);
+    let (files, _) = gather_gz_files(&[&data], &args.gather_options().unwrap()).unwrap();
+    let (files, total_size) =
+        super::skip_checkpointed(files, &super::read_checkpoint(&checkpoint).unwrap());
+    assert_eq!(files.len(), 1);
+    assert_eq!(total_size, std::fs::metadata(&pending).unwrap().len());
+
+    super::process_files(
+        &files,
+        &args.sieve_options(&data).unwrap(),
+        total_size,
+        &args.run_options().unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(read_gz_lines(&done), vec!["keep", "drop"]);
+    assert_eq!(read_gz_lines(&pending), vec!["keep"]);
+    let recorded = super::read_checkpoint(&checkpoint).unwrap();
+    assert!(recorded.contains(&done.canonicalize().unwrap()));
+    assert!(recorded.contains(&pending.canonicalize().unwrap()));
+}
+
+#[test]
+fn test_missing_checkpoint_is_empty() {
+    let dir = tempdir().unwrap();
+    let recorded = super::read_checkpoint(&dir.path().join("missing")).unwrap();
+    assert!(recorded.is_empty());
+}
+
+#[test]
+fn test_sieve_options_builder() {
+    let options = SieveOptions::builder(["ERROR", "WARN"])
+        .match_kind(MatchKind::StartsWith)
+        .mode(Mode::Keep)
+        .dry_run(true)
+        .compression_level(3)
+        .max_removal_ratio(0.5)
+        .backup_suffix(".orig".to_string())
+        .output_dir("/logs", "/filtered")
+        .build()
+        .unwrap();
+
+    assert_eq!(options.patterns, vec!["ERROR", "WARN"]);
+    assert_eq!(options.match_kind, MatchKind::StartsWith);
+    assert_eq!(options.mode, Mode::Keep);
+    assert!(options.dry_run);
+    assert_eq!(options.compression_level, Some(3));
+    assert_eq!(options.max_removal_ratio, Some(0.5));
+    assert_eq!(options.backup_suffix.as_deref(), Some(".orig"));
+    let output_dir = options.output_dir.unwrap();
+    assert_eq!(output_dir.root, Path::new("/logs"));
+    assert_eq!(output_dir.dir, Path::new("/filtered"));
+    assert!(!options.count_only);
+    assert_eq!(options.delimiter, Delimiter(b'\n'));
+}
+
+#[test]
+fn test_sieve_options_builder_rejects_invalid_settings() {
+    let too_high = SieveOptions::builder(["x"]).compression_level(10).build();
+    assert!(matches!(too_high, Err(SieveError::InvalidOptions(_))));
+
+    let bad_ratio = SieveOptions::builder(["x"]).max_removal_ratio(1.5).build();
+    assert!(matches!(bad_ratio, Err(SieveError::InvalidOptions(_))));
+
+    let conflicting = SieveOptions::builder(["x"])
+        .delete_emptied(true)
+        .no_empty(true)
+        .build();
+    assert!(matches!(conflicting, Err(SieveError::InvalidOptions(_))));
+}
+
+#[test]
+fn test_file_result_and_run_summary_sizes() {
+    let dir = tempdir().unwrap();
+    let changed = dir.path().join("changed.gz");
+    let untouched = dir.path().join("untouched.gz");
+    let lines: Vec<String> = (0..500).map(|i| format!("line {i} drop")).collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    write_gz_lines(&changed, &lines);
+    write_gz_lines(&untouched, &["keep"]);
+    let changed_before = std::fs::metadata(&changed).unwrap().len();
+    let untouched_size = std::fs::metadata(&untouched).unwrap().len();
+
+    let options = SieveOptions::builder(["line 1"]).build().unwrap();
+    let matcher = Matcher::new(&options.patterns, options.match_kind).unwrap();
+    let result = filter_lines(&untouched, &matcher, &options).unwrap();
+    assert_eq!(
+        result,
+        FileResult {
+            read: 1,
+            removed: 0,
+            collapsed: 0,
+            bytes_before: untouched_size,
+            bytes_after: untouched_size,
+        }
+    );
+
+    let (files, total_size) = gather_gz_files(&[dir.path()], &GatherOptions::default()).unwrap();
+    let summary = super::process_files(
+        &files,
+        &options,
+        total_size,
+        &RunOptions {
+            threads: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let changed_after = std::fs::metadata(&changed).unwrap().len();
+    assert!(changed_after < changed_before);
+    assert_eq!(summary.total_lines_read, 501);
+    // "line 1", "line 1x" and "line 1xx"
+    assert_eq!(summary.total_lines_removed, 111);
+    assert_eq!(summary.total_bytes_before, changed_before + untouched_size);
+    assert_eq!(summary.total_bytes_after, changed_after + untouched_size);
+}